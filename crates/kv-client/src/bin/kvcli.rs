@@ -1,36 +1,711 @@
-use kv_shared::io::KVKey;
-use nix::sys::socket::{UnixAddr};
+use std::io::Write;
+use std::path::PathBuf;
+use kv_shared::io::{KVConnection, KVKey, KVStatus, KvError};
 use nix::unistd::{close};
-use std::str::from_utf8;
-use kv_client::{kvc_delete, kvc_get, kvc_set, new_client_kvconnection };
+use kv_client::{DEFAULT_SOCKET_PATH, kvc_connect, kvc_count, kvc_delete, kvc_exists, kvc_get, kvc_incr, kvc_set, kvc_strlen};
+use kv_client::encoding::{base64_encode, format_key, hex_encode, json_string, parse_key};
+
+const USAGE: &str = "usage: kvcli [--socket <path>] [--output raw|hex|json] [<command> [args]]
+
+without a command kvcli reads commands interactively over one connection.
+options may come anywhere, after -- every argument is taken as it is
+
+commands:
+  get <key>               print the value at key
+  set <key> <value>       store value at key
+  set <key> -f <file>     store the contents of file at key, - for stdin
+  del <key>               delete key
+  incr <key> [delta]      add delta, default 1, to the integer at key and print it
+  exists <key>            print whether key is present
+  count [prefix]          print the number of keys, only those beginning with prefix if given
+  strlen <key>            print the length of the value at key
+
+keys may be plain text, hex:<digits> or b64:<base64>
+
+exit status: 0 ok, 1 key not found, 2 bad usage, 3 error";
+
+/// Names parse_command knows
+const COMMANDS: &[&str] = &["get", "set", "del", "incr", "exists", "count", "strlen"];
+
+const EXIT_NOT_FOUND: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_ERROR: i32 = 3;
+
+/// How get prints values
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Output {
+    /// the value bytes as they are
+    Raw,
+    /// hex digits and a newline
+    Hex,
+    /// one JSON object per command
+    Json,
+}
+
+/// One request to the server, parsed from the command line
+enum Command {
+    Get(KVKey),
+    Set(KVKey, Vec<u8>),
+    Del(KVKey),
+    Incr(KVKey, i64),
+    Exists(KVKey),
+    Count(Option<KVKey>),
+    StrLen(KVKey),
+}
+
+/// Why a command did not succeed, each with its own exit status
+enum Failure {
+    NotFound,
+    Usage(String),
+    Error(String),
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::NotFound => EXIT_NOT_FOUND,
+            Failure::Usage(_) => EXIT_USAGE,
+            Failure::Error(_) => EXIT_ERROR,
+        }
+    }
+}
 
 fn main() {
-    
-    // todo get cli args, parse them...
-    
-    println!("client: start");
-    let mut connection = new_client_kvconnection().unwrap();
-
-    /* try GET */
-    let get_key = KVKey::new("test").unwrap();
-    let get_result = kvc_get(&mut connection, &get_key).unwrap();
-    let get_msg = from_utf8(&get_result).expect("invalid utf-8");
-    println!("client: got '{}' from get()", get_msg);
-
-    /* try SET */
-    let set_key = KVKey::new("test").unwrap();
-    let set_val: Vec<u8> = "hello darling".as_bytes().to_vec();
-    let set_result = kvc_set(&mut connection, &set_key, &set_val).unwrap();
-    let set_msg = from_utf8(&set_result).expect("invalid utf-8");
-    println!("client: got '{}' from set()", set_msg);
-
-    /* try DEL */
-    let del_key = KVKey::new("test").unwrap();
-    let del_result = kvc_delete(&mut connection, &del_key).unwrap();
-    let del_msg = from_utf8(&del_result).expect("invalid utf-8");
-    println!("client: got '{}' from del()", del_msg);
+    let mut socket = PathBuf::from(DEFAULT_SOCKET_PATH);
+    let mut output = Output::Raw;
 
+    /* options may come before, after or between the command's arguments, so a
+     * value that looks like one is refused unless it follows -- */
+    let mut args = std::env::args().skip(1);
+    let mut words: Vec<String> = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--socket" => match args.next() {
+                Some(path) => socket = PathBuf::from(path),
+                None => exit_usage("--socket needs a path"),
+            },
+            "--output" => match args.next().as_deref() {
+                Some("raw") => output = Output::Raw,
+                Some("hex") => output = Output::Hex,
+                Some("json") => output = Output::Json,
+                Some(other) => exit_usage(&format!("unknown output mode '{}'", other)),
+                None => exit_usage("--output needs a mode"),
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            },
+            "--" => words.extend(args.by_ref()),
+            option if option.starts_with("--") => exit_usage(&format!("unknown option '{}'", option)),
+            _ => words.push(arg),
+        }
+    }
+    if words.is_empty() {
+        std::process::exit(repl::run(&socket, output));
+    }
+    let name = words.remove(0);
+    let args = words;
+
+    /* parse before connecting so bad usage is reported without a server */
+    let command = match parse_command(&name, &args) {
+        Ok(command) => command,
+        Err(failure) => exit_with(failure),
+    };
+    let mut connection = match kvc_connect(&socket) {
+        Ok(connection) => connection,
+        Err(e) => exit_with(Failure::Error(format!("connect to {} failed: {}", socket.display(), e))),
+    };
+    let result = execute(&mut connection, output, &command, false);
     close(connection.fd).expect("close sockfd failed");
-    println!("client: stop");
+    if let Err(failure) = result {
+        exit_with(failure);
+    }
+}
+
+fn exit_usage(msg: &str) -> ! {
+    exit_with(Failure::Usage(msg.to_string()))
+}
+
+fn exit_with(failure: Failure) -> ! {
+    match &failure {
+        Failure::NotFound => eprintln!("kvcli: key not found"),
+        Failure::Usage(msg) => eprintln!("kvcli: {}\n\n{}", msg, USAGE),
+        Failure::Error(msg) => eprintln!("kvcli: {}", msg),
+    }
+    std::process::exit(failure.exit_code());
+}
+
+fn key_arg(arg: &str) -> Result<KVKey, Failure> {
+    parse_key(arg).map_err(|e| Failure::Usage(format!("bad key '{}': {}", arg, e)))
+}
+
+/// Command named name with its arguments
+fn parse_command(name: &str, args: &[String]) -> Result<Command, Failure> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match (name, args.as_slice()) {
+        ("get", [key]) => Ok(Command::Get(key_arg(key)?)),
+        ("set", [key, "-f", path]) => {
+            let value = match *path {
+                "-" => {
+                    let mut value = Vec::new();
+                    std::io::Read::read_to_end(&mut std::io::stdin(), &mut value).map(|_| value)
+                },
+                path => std::fs::read(path),
+            };
+            match value {
+                Ok(value) => Ok(Command::Set(key_arg(key)?, value)),
+                Err(e) => Err(Failure::Error(format!("read {} failed: {}", path, e))),
+            }
+        },
+        ("set", [_, "-f"]) => Err(Failure::Usage("-f needs a file".to_string())),
+        ("set", [key, value]) => Ok(Command::Set(key_arg(key)?, value.as_bytes().to_vec())),
+        ("del", [key]) => Ok(Command::Del(key_arg(key)?)),
+        ("incr", [key]) => Ok(Command::Incr(key_arg(key)?, 1)),
+        ("incr", [key, delta]) => match delta.parse() {
+            Ok(delta) => Ok(Command::Incr(key_arg(key)?, delta)),
+            Err(_) => Err(Failure::Usage(format!("bad delta '{}'", delta))),
+        },
+        ("exists", [key]) => Ok(Command::Exists(key_arg(key)?)),
+        ("count", []) => Ok(Command::Count(None)),
+        ("count", [prefix]) => Ok(Command::Count(Some(key_arg(prefix)?))),
+        ("strlen", [key]) => Ok(Command::StrLen(key_arg(key)?)),
+        (name, _) if COMMANDS.contains(&name) => Err(Failure::Usage(format!("wrong arguments for {}", name))),
+        _ => Err(Failure::Usage(format!("unknown command '{}'", name))),
+    }
+}
+
+/// Failure for an error from the server or the connection
+fn failed(command: &str, e: KvError) -> Failure {
+    match e {
+        KvError::Status(KVStatus::NotFound) => Failure::NotFound,
+        e => Failure::Error(format!("{} failed: {}", command, e)),
+    }
+}
+
+/// Send command over connection and print its result in output mode.
+/// Interactive output ends raw values with a newline so the prompt starts on its own line
+fn execute(connection: &mut KVConnection, output: Output, command: &Command, interactive: bool) -> Result<(), Failure> {
+    match command {
+        Command::Get(key) => match kvc_get(connection, key).map_err(|e| failed("get", e))? {
+            Some(value) => print_value(output, key, &value, interactive),
+            None => Err(Failure::NotFound),
+        },
+        Command::Set(key, value) => {
+            kvc_set(connection, key, value).map_err(|e| failed("set", e))?;
+            if output == Output::Json {
+                println!("{{\"key\":{},\"ok\":true}}", json_string(&format_key(key)));
+            }
+            Ok(())
+        },
+        Command::Del(key) => {
+            let deleted = kvc_delete(connection, key).map_err(|e| failed("del", e))?;
+            if output == Output::Json {
+                println!("{{\"key\":{},\"deleted\":{}}}", json_string(&format_key(key)), deleted);
+            }
+            match deleted {
+                true => Ok(()),
+                false => Err(Failure::NotFound),
+            }
+        },
+        Command::Incr(key, delta) => {
+            let value = kvc_incr(connection, key, *delta).map_err(|e| failed("incr", e))?;
+            match output {
+                Output::Json => println!("{{\"key\":{},\"value\":{}}}", json_string(&format_key(key)), value),
+                _ => println!("{}", value),
+            }
+            Ok(())
+        },
+        Command::Exists(key) => {
+            let exists = kvc_exists(connection, key).map_err(|e| failed("exists", e))?;
+            match output {
+                Output::Json => println!("{{\"key\":{},\"exists\":{}}}", json_string(&format_key(key)), exists),
+                _ => println!("{}", exists),
+            }
+            match exists {
+                true => Ok(()),
+                false => Err(Failure::NotFound),
+            }
+        },
+        Command::Count(prefix) => {
+            let count = kvc_count(connection, prefix.as_ref()).map_err(|e| failed("count", e))?;
+            match (output, prefix) {
+                (Output::Json, Some(prefix)) => println!("{{\"prefix\":{},\"count\":{}}}", json_string(&format_key(prefix)), count),
+                (Output::Json, None) => println!("{{\"count\":{}}}", count),
+                _ => println!("{}", count),
+            }
+            Ok(())
+        },
+        Command::StrLen(key) => match kvc_strlen(connection, key).map_err(|e| failed("strlen", e))? {
+            Some(len) => {
+                match output {
+                    Output::Json => println!("{{\"key\":{},\"len\":{}}}", json_string(&format_key(key)), len),
+                    _ => println!("{}", len),
+                }
+                Ok(())
+            },
+            None => Err(Failure::NotFound),
+        },
+    }
+}
+
+/// Print a value read from key. JSON carries utf-8 values as a string and
+/// anything else base64 encoded under value_b64
+fn print_value(output: Output, key: &KVKey, value: &[u8], interactive: bool) -> Result<(), Failure> {
+    let mut stdout = std::io::stdout().lock();
+    let written = match output {
+        Output::Raw if interactive && !value.ends_with(b"\n") => stdout.write_all(value).and_then(|_| writeln!(stdout)),
+        Output::Raw => stdout.write_all(value),
+        Output::Hex => writeln!(stdout, "{}", hex_encode(value)),
+        Output::Json => match std::str::from_utf8(value) {
+            Ok(text) => writeln!(stdout, "{{\"key\":{},\"value\":{}}}", json_string(&format_key(key)), json_string(text)),
+            Err(_) => writeln!(stdout, "{{\"key\":{},\"value_b64\":\"{}\"}}", json_string(&format_key(key)), base64_encode(value)),
+        },
+    };
+    written.and_then(|_| stdout.flush()).map_err(|e| Failure::Error(format!("write to stdout failed: {}", e)))
+}
+
+/// Interactive prompt over one persistent connection
+mod repl {
+    use std::io::{BufRead, Write};
+    use std::path::{Path, PathBuf};
+    use nix::errno::Errno;
+    use nix::sys::termios::{InputFlags, LocalFlags, SetArg, SpecialCharacterIndices, tcgetattr, tcsetattr};
+    use nix::unistd::{close, isatty, read};
+    use kv_client::{kvc_connect, kvc_last_rtt};
+
+    use super::{COMMANDS, EXIT_ERROR, Failure, Output, USAGE, execute, parse_command};
+
+    /// Words the prompt understands besides COMMANDS, completed by tab alike
+    const PROMPT_WORDS: &[&str] = &["help", "quit", "exit"];
+
+    /// Most lines kept in the history file
+    const HISTORY_MAX: usize = 1000;
+
+    const PROMPT: &str = "kv> ";
+
+    /// Run the prompt until quit or end of input, returns the exit status
+    pub fn run(socket: &Path, output: Output) -> i32 {
+        let mut connection = match kvc_connect(socket) {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("kvcli: connect to {} failed: {}", socket.display(), e);
+                return EXIT_ERROR;
+            }
+        };
+        let tty = isatty(std::io::stdin()).unwrap_or(false);
+        let color = isatty(std::io::stdout()).unwrap_or(false);
+        if tty {
+            println!("connected to {} with protocol version {}, help lists the commands", socket.display(), connection.version);
+        }
+
+        /* piped input is a script, its lines are neither history nor worth timing */
+        let history_path = std::env::var_os("HOME").filter(|_| tty).map(|home| PathBuf::from(home).join(".kvcli_history"));
+        let mut editor = LineEditor::new(tty, history_path.as_deref().map(load_history).unwrap_or_default());
+
+        loop {
+            let line = match editor.read_line(PROMPT) {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("kvcli: read from stdin failed: {}", e);
+                    break;
+                }
+            };
+            editor.add_history(&line);
+            let words = match split_words(&line) {
+                Ok(words) => words,
+                Err(msg) => {
+                    show_error(color, msg);
+                    continue;
+                }
+            };
+            let (name, args) = match words.split_first() {
+                Some((name, args)) => (name.as_str(), args),
+                None => continue,
+            };
+            match name {
+                "quit" | "exit" => break,
+                "help" => {
+                    println!("{}", USAGE.split("commands:\n").nth(1).unwrap_or(USAGE));
+                    println!("  help                    print this list\n  quit, exit              leave the prompt");
+                    continue;
+                },
+                _ => (),
+            }
+
+            let command = match parse_command(name, args) {
+                Ok(command) => command,
+                Err(failure) => {
+                    show_failure(color, name, &failure);
+                    continue;
+                }
+            };
+            let result = execute(&mut connection, output, &command, true);
+            /* timed around the last request alone, parsing and printing are left out */
+            let rtt = kvc_last_rtt(&connection);
+            if let Err(failure) = &result {
+                show_failure(color, name, failure);
+            }
+            match (tty, color) {
+                (false, _) => (),
+                (true, true) => println!("\x1b[2m({:.3} ms)\x1b[0m", rtt.as_secs_f64() * 1000.0),
+                (true, false) => println!("({:.3} ms)", rtt.as_secs_f64() * 1000.0),
+            }
+        }
+
+        if let Some(path) = history_path {
+            save_history(&path, &editor.history);
+        }
+        close(connection.fd).expect("close sockfd failed");
+        0
+    }
+
+    fn show_error(color: bool, msg: &str) {
+        match color {
+            true => println!("\x1b[31m(error)\x1b[0m {}", msg),
+            false => println!("(error) {}", msg),
+        }
+    }
+
+    /// Print why command name failed, with its usage line if it was misused
+    fn show_failure(color: bool, name: &str, failure: &Failure) {
+        match failure {
+            Failure::NotFound => match color {
+                true => println!("\x1b[33m(not found)\x1b[0m"),
+                false => println!("(not found)"),
+            },
+            Failure::Usage(msg) => {
+                show_error(color, msg);
+                let usage = USAGE.lines().filter(|line| line.trim_start().starts_with(&format!("{} ", name)));
+                for line in usage {
+                    /* just the synopsis, without the description after it */
+                    println!("  usage: {}", line.trim().split("  ").next().unwrap_or_default());
+                }
+            },
+            Failure::Error(msg) => show_error(color, msg),
+        }
+    }
+
+    /// Split line into words at whitespace. Single quotes keep text as it is, double
+    /// quotes allow the escapes \" \\ \n and \t
+    fn split_words(line: &str) -> Result<Vec<String>, &'static str> {
+        let mut words = Vec::new();
+        let mut chars = line.chars().peekable();
+        loop {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            if chars.peek().is_none() {
+                return Ok(words);
+            }
+            let mut word = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                match c {
+                    '\'' => loop {
+                        match chars.next() {
+                            Some('\'') => break,
+                            Some(c) => word.push(c),
+                            None => return Err("unterminated single quote"),
+                        }
+                    },
+                    '"' => loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => match chars.next() {
+                                Some('n') => word.push('\n'),
+                                Some('t') => word.push('\t'),
+                                Some(c) => word.push(c),
+                                None => return Err("unterminated double quote"),
+                            },
+                            Some(c) => word.push(c),
+                            None => return Err("unterminated double quote"),
+                        }
+                    },
+                    c => word.push(c),
+                }
+            }
+            words.push(word);
+        }
+    }
+
+    fn load_history(path: &Path) -> Vec<String> {
+        match std::fs::File::open(path) {
+            Ok(file) => std::io::BufReader::new(file).lines().map_while(Result::ok).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    fn save_history(path: &Path, history: &[String]) {
+        let start = history.len().saturating_sub(HISTORY_MAX);
+        let mut text = history[start..].join("\n");
+        text.push('\n');
+        if let Err(e) = std::fs::write(path, text) {
+            eprintln!("kvcli: write {} failed: {}", path.display(), e);
+        }
+    }
+
+    /// Keys the line editor acts on
+    enum Key {
+        Char(char),
+        Enter,
+        Backspace,
+        Delete,
+        Left,
+        Right,
+        Home,
+        End,
+        Up,
+        Down,
+        Tab,
+        /// ctrl-c, drops the line
+        Cancel,
+        /// ctrl-d, ends input on an empty line
+        Eof,
+        /// ctrl-u, deletes up to the cursor
+        KillStart,
+        /// ctrl-k, deletes from the cursor
+        KillEnd,
+        Ignored,
+    }
+
+    /// Line input with history and completion when stdin is a terminal, plain lines otherwise
+    struct LineEditor {
+        tty: bool,
+        history: Vec<String>,
+    }
+
+    impl LineEditor {
+        fn new(tty: bool, history: Vec<String>) -> Self {
+            Self { tty, history }
+        }
+
+        fn add_history(&mut self, line: &str) {
+            if !line.trim().is_empty() && self.history.last().map(String::as_str) != Some(line) {
+                self.history.push(line.to_string());
+            }
+        }
+
+        /// Next line of input, None at the end of it
+        fn read_line(&mut self, prompt: &str) -> Result<Option<String>, Errno> {
+            if !self.tty {
+                let mut line = String::new();
+                return match std::io::stdin().read_line(&mut line) {
+                    Ok(0) => Ok(None),
+                    Ok(_) => Ok(Some(line.trim_end_matches(['\n', '\r']).to_string())),
+                    Err(e) => Err(Errno::from_raw(e.raw_os_error().unwrap_or(0))),
+                };
+            }
+
+            /* keys arrive one at a time and unechoed only while reading a line,
+             * commands run with the terminal as it was, so ctrl-c still interrupts them */
+            let saved = tcgetattr(std::io::stdin())?;
+            let mut raw = saved.clone();
+            raw.local_flags.remove(LocalFlags::ICANON | LocalFlags::ECHO | LocalFlags::ISIG | LocalFlags::IEXTEN);
+            raw.input_flags.remove(InputFlags::IXON | InputFlags::ICRNL);
+            raw.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+            raw.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+            tcsetattr(std::io::stdin(), SetArg::TCSADRAIN, &raw)?;
+            let result = self.edit(prompt);
+            tcsetattr(std::io::stdin(), SetArg::TCSADRAIN, &saved)?;
+            result
+        }
+
+        fn edit(&mut self, prompt: &str) -> Result<Option<String>, Errno> {
+            let mut line: Vec<char> = Vec::new();
+            let mut pos: usize = 0;
+            /* position in history, history.len() being the line being typed, kept in typed */
+            let mut browse = self.history.len();
+            let mut typed: Vec<char> = Vec::new();
+
+            redraw(prompt, &line, pos);
+            loop {
+                match read_key()? {
+                    Key::Enter => {
+                        print_raw("\r\n");
+                        return Ok(Some(line.into_iter().collect()));
+                    },
+                    Key::Char(c) => {
+                        line.insert(pos, c);
+                        pos += 1;
+                    },
+                    Key::Backspace if pos > 0 => {
+                        pos -= 1;
+                        line.remove(pos);
+                    },
+                    Key::Delete if pos < line.len() => {
+                        line.remove(pos);
+                    },
+                    Key::Eof if line.is_empty() => {
+                        print_raw("\r\n");
+                        return Ok(None);
+                    },
+                    Key::Eof if pos < line.len() => {
+                        line.remove(pos);
+                    },
+                    Key::Left if pos > 0 => pos -= 1,
+                    Key::Right if pos < line.len() => pos += 1,
+                    Key::Home => pos = 0,
+                    Key::End => pos = line.len(),
+                    Key::Up if browse > 0 => {
+                        if browse == self.history.len() {
+                            typed = line.clone();
+                        }
+                        browse -= 1;
+                        line = self.history[browse].chars().collect();
+                        pos = line.len();
+                    },
+                    Key::Down if browse < self.history.len() => {
+                        browse += 1;
+                        line = match self.history.get(browse) {
+                            Some(entry) => entry.chars().collect(),
+                            None => typed.clone(),
+                        };
+                        pos = line.len();
+                    },
+                    Key::Tab => pos = complete(prompt, &mut line, pos),
+                    Key::Cancel => {
+                        print_raw("^C\r\n");
+                        line.clear();
+                        pos = 0;
+                        browse = self.history.len();
+                    },
+                    Key::KillStart => {
+                        line.drain(..pos);
+                        pos = 0;
+                    },
+                    Key::KillEnd => line.truncate(pos),
+                    _ => (),
+                }
+                redraw(prompt, &line, pos);
+            }
+        }
+    }
+
+    fn print_raw(s: &str) {
+        let mut stdout = std::io::stdout().lock();
+        let _ = stdout.write_all(s.as_bytes()).and_then(|_| stdout.flush());
+    }
+
+    /// Rewrite the prompt and line in place and put the cursor at pos
+    fn redraw(prompt: &str, line: &[char], pos: usize) {
+        let mut s = format!("\r{}{}\x1b[K", prompt, line.iter().collect::<String>());
+        if pos < line.len() {
+            s.push_str(&format!("\x1b[{}D", line.len() - pos));
+        }
+        print_raw(&s);
+    }
+
+    /// Complete the command name before the cursor, listing the candidates when there
+    /// are several and none is longer. Returns the new cursor position
+    fn complete(prompt: &str, line: &mut Vec<char>, pos: usize) -> usize {
+        let word: String = line[..pos].iter().collect();
+        if word.contains(char::is_whitespace) {
+            return pos;
+        }
+        let matches: Vec<&str> = COMMANDS.iter().chain(PROMPT_WORDS).copied().filter(|w| w.starts_with(&word)).collect();
+        let completion = match matches.as_slice() {
+            [] => {
+                print_raw("\x07");
+                return pos;
+            },
+            [only] => format!("{} ", only),
+            [first, rest @ ..] => {
+                let common = rest.iter().fold(first.len(), |len, w| {
+                    first.bytes().zip(w.bytes()).take(len).take_while(|(a, b)| a == b).count()
+                });
+                if common == word.len() {
+                    print_raw(&format!("\r\n{}\r\n", matches.join("  ")));
+                    redraw(prompt, line, pos);
+                    return pos;
+                }
+                first[..common].to_string()
+            },
+        };
+        let added: Vec<char> = completion.chars().skip(word.chars().count()).collect();
+        line.splice(pos..pos, added.iter().copied());
+        pos + added.len()
+    }
+
+    fn read_byte() -> Result<u8, Errno> {
+        let mut byte = [0u8; 1];
+        loop {
+            match read(std::io::stdin(), &mut byte) {
+                Ok(0) => return Ok(4), /* end of input reads as ctrl-d */
+                Ok(_) => return Ok(byte[0]),
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
 
+    /// Next key from the terminal, decoding escape sequences and utf-8
+    fn read_key() -> Result<Key, Errno> {
+        let key = match read_byte()? {
+            b'\r' | b'\n' => Key::Enter,
+            0x7f | 0x08 => Key::Backspace,
+            b'\t' => Key::Tab,
+            0x01 => Key::Home,
+            0x02 => Key::Left,
+            0x03 => Key::Cancel,
+            0x04 => Key::Eof,
+            0x05 => Key::End,
+            0x06 => Key::Right,
+            0x0b => Key::KillEnd,
+            0x0e => Key::Down,
+            0x10 => Key::Up,
+            0x15 => Key::KillStart,
+            0x1b => match read_byte()? {
+                b'[' => match read_byte()? {
+                    b'A' => Key::Up,
+                    b'B' => Key::Down,
+                    b'C' => Key::Right,
+                    b'D' => Key::Left,
+                    b'H' => Key::Home,
+                    b'F' => Key::End,
+                    digit @ b'0'..=b'9' => {
+                        /* ESC [ <number> ~, e.g. 3~ for delete */
+                        let mut number = vec![digit];
+                        loop {
+                            match read_byte()? {
+                                b'~' => break,
+                                b @ b'0'..=b'9' => number.push(b),
+                                _ => return Ok(Key::Ignored),
+                            }
+                        }
+                        match number.as_slice() {
+                            b"1" | b"7" => Key::Home,
+                            b"3" => Key::Delete,
+                            b"4" | b"8" => Key::End,
+                            _ => Key::Ignored,
+                        }
+                    },
+                    _ => Key::Ignored,
+                },
+                b'O' => match read_byte()? {
+                    b'H' => Key::Home,
+                    b'F' => Key::End,
+                    _ => Key::Ignored,
+                },
+                _ => Key::Ignored,
+            },
+            b if b < 0x20 => Key::Ignored,
+            b if b < 0x80 => Key::Char(b as char),
+            b => {
+                /* lead byte of a utf-8 sequence, followed by its continuation bytes */
+                let mut bytes = vec![b];
+                let len = match b {
+                    0xc0..=0xdf => 2,
+                    0xe0..=0xef => 3,
+                    0xf0..=0xf7 => 4,
+                    _ => 1,
+                };
+                while bytes.len() < len {
+                    bytes.push(read_byte()?);
+                }
+                match std::str::from_utf8(&bytes).ok().and_then(|s| s.chars().next()) {
+                    Some(c) => Key::Char(c),
+                    None => Key::Ignored,
+                }
+            },
+        };
+        Ok(key)
+    }
 }
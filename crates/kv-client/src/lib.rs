@@ -1,11 +1,22 @@
 //will contain implementations for CLI get, set, delete...
-use nix::sys::socket::{socket, connect, AddressFamily, SockFlag, SockType, UnixAddr};
+use nix::sys::socket::{socket, connect, SockFlag, UnixAddr};
 use std::os::fd::{AsRawFd};
-use nix::{errno::Errno};
-use kv_shared::io::{KVConnection, KVKey, KVMsg, KVMsgType};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use kv_shared::io::{DEFAULT_MAX_FRAME_SIZE, FEATURE_PIPELINING, GET_VERSION_SINCE, KVConnection, KVHello, KVIncr, KVKey, KVKeyList, KVMsg, KVMsgType, KVPair, KVPairList, KVPrefixScan, KVResultList, KVScan, KVScanPage, KVStatus, KVTxn, KVTxnOp, KvError, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, REQID_SINCE};
 
-pub fn new_client_kvconnection() -> Result<KVConnection, Errno>{
-    let sock_addr = UnixAddr::new("./kv.sock").unwrap();
+/// Socket the server listens on when started from the same directory
+pub const DEFAULT_SOCKET_PATH: &str = "./kv.sock";
+
+pub fn new_client_kvconnection() -> Result<KVConnection, KvError>{
+    kvc_connect(Path::new(DEFAULT_SOCKET_PATH))
+}
+
+/// Connect to the server listening on the unix socket at path and say hello
+pub fn kvc_connect(path: &Path) -> Result<KVConnection, KvError>{
+    let sock_addr = UnixAddr::new(path)?;
     let sockfd = match socket(
         nix::sys::socket::AddressFamily::Unix, 
         nix::sys::socket::SockType::Stream,
@@ -15,63 +26,648 @@ pub fn new_client_kvconnection() -> Result<KVConnection, Errno>{
         Ok(fd) => fd,
         Err(e) => {
             eprintln!("new_as_client socket error: {}", e);
-            return Err(e);
+            return Err(KvError::Io(e));
         }
     };
 
-    let _rc_conn = match connect(sockfd.as_raw_fd(), &sock_addr){
-        Ok(rc) => rc,
+    match connect(sockfd.as_raw_fd(), &sock_addr){
+        Ok(_) => (),
         Err(e) => {
             eprintln!("new_as_client connect error: {}", e);
-            return Err(e);
+            return Err(KvError::Io(e));
         } 
     };
 
-    return Ok(KVConnection {
+    let mut connection = KVConnection {
         fd: sockfd,
-        mtu: 1024,
-    });
+        max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        version: 0,
+        features: 0,
+        next_reqid: 0,
+        last_sendtime: Duration::ZERO,
+    };
+    kvc_hello(&mut connection)?;
+    Ok(connection)
+}
+
+/// Features this client asks the server for
+pub const CLIENT_FEATURES: u64 = FEATURE_PIPELINING;
+
+/// Agree on a protocol version and features with the server, recorded on connection
+pub fn kvc_hello(connection: &mut KVConnection) -> Result<(), KvError> {
+    let ours = KVHello {
+        min_version: MIN_PROTOCOL_VERSION,
+        max_version: PROTOCOL_VERSION,
+        features: CLIENT_FEATURES,
+    };
+    connection.send_kvmsg(KVMsg::new(KVMsgType::Hello, ours.to_bytes()))?;
+    let response = connection.recv_kvmsg()?;
+
+    match response.reply_status()? {
+        (KVStatus::Ok, body) => {
+            let agreed = KVHello::from_bytes(body)?;
+            connection.version = agreed.max_version;
+            connection.features = agreed.features;
+            Ok(())
+        },
+        (KVStatus::Incompatible, body) => {
+            let theirs = KVHello::from_bytes(body)?;
+            Err(KvError::Incompatible {
+                ours: (ours.min_version, ours.max_version),
+                theirs: (theirs.min_version, theirs.max_version),
+            })
+        },
+        (status, _) => Err(KvError::Status(status)),
+    }
+}
+
+thread_local! {
+    /// when the reply to the last request this thread waited on arrived
+    static LAST_RECVTIME: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+}
+
+fn mark_received() {
+    LAST_RECVTIME.set(SystemTime::now().duration_since(UNIX_EPOCH).unwrap());
+}
+
+/// Round trip of the last request this thread waited on, from its sendtime on connection
+/// to the arrival of its reply. Zero if no reply to it came back
+pub fn kvc_last_rtt(connection: &KVConnection) -> Duration {
+    LAST_RECVTIME.get().saturating_sub(connection.last_sendtime)
+}
+
+/// Send msg and wait for its reply
+fn kvc_request(connection: &mut KVConnection, msg: KVMsg) -> Result<KVMsg, KvError> {
+    let reqid = connection.send_request(msg)?;
+    let response = connection.recv_kvmsg()?;
+    mark_received();
+    if response.reqid != reqid {
+        return Err(unmatched_reply(&response));
+    }
+    Ok(response)
+}
+
+/// Error for a reply that answers none of our requests. The server sends request id 0
+/// for a frame it could not read an id from, that error fails the whole connection
+fn unmatched_reply(response: &KVMsg) -> KvError {
+    match response.reply_status() {
+        Ok((status, _)) if response.reqid == 0 && response.msgtype == KVMsgType::ErrorReturn => KvError::Status(status),
+        _ => KvError::Protocol("reply for another request"),
+    }
+}
+
+/// Value and version of a GetReturn sent under protocol version, the version is 0
+/// from servers older than GET_VERSION_SINCE
+fn get_reply(response: &KVMsg, version: u16) -> Result<Option<(Vec<u8>, u64)>, KvError> {
+    match response.reply_status()? {
+        (KVStatus::Ok, payload) if version >= GET_VERSION_SINCE => {
+            if payload.len() < 8 {
+                return Err(KvError::Protocol("get reply shorter than its version"));
+            }
+            let value_version = u64::from_le_bytes(payload[0..8].try_into().unwrap());
+            Ok(Some((payload[8..].to_vec(), value_version)))
+        },
+        (KVStatus::Ok, value) => Ok(Some((value.to_vec(), 0))),
+        (KVStatus::NotFound, _) => Ok(None),
+        (status, _) => Err(KvError::Status(status)),
+    }
 }
 
-pub fn kvc_get(connection: &mut KVConnection, key: &KVKey) -> Result<Vec<u8>, Errno> {
+fn set_reply(response: &KVMsg) -> Result<(), KvError> {
+    match response.reply_status()? {
+        (KVStatus::Ok, _) => Ok(()),
+        (status, _) => Err(KvError::Status(status)),
+    }
+}
+
+/// Ok as true and NotFound as false, for the replies of Delete and Exists
+fn found_reply(response: &KVMsg) -> Result<bool, KvError> {
+    match response.reply_status()? {
+        (KVStatus::Ok, _) => Ok(true),
+        (KVStatus::NotFound, _) => Ok(false),
+        (status, _) => Err(KvError::Status(status)),
+    }
+}
 
+/// Get the value stored at key, None if the key is not present
+pub fn kvc_get(connection: &mut KVConnection, key: &KVKey) -> Result<Option<Vec<u8>>, KvError> {
+    Ok(kvc_get_versioned(connection, key)?.map(|(value, _)| value))
+}
+
+/// Get the value stored at key and its version, for use with kvc_cas
+pub fn kvc_get_versioned(connection: &mut KVConnection, key: &KVKey) -> Result<Option<(Vec<u8>, u64)>, KvError> {
     let msg = KVMsg::new(KVMsgType::Get, key.to_bytes());
+    let response = kvc_request(connection, msg)?;
+    get_reply(&response, connection.version)
+}
 
-    match connection.send_kvmsg(msg){
-        Ok(y) => y,
-        Err(e) => return Err(e),
-    };
-    let response = connection.recv_kvmsg().unwrap();
+/// Store value at key
+pub fn kvc_set(connection: &mut KVConnection, key: &KVKey, value: &[u8]) -> Result<(), KvError> {
+    let pair = KVPair::new(*key, value.to_vec());
+    let msg = KVMsg::new(KVMsgType::Set, pair.to_bytes());
+    set_reply(&kvc_request(connection, msg)?)
+}
 
-    Ok(response.msg)
+/// Store value at key for ttl seconds, after which the key reads as not present
+pub fn kvc_set_ttl(connection: &mut KVConnection, key: &KVKey, value: &[u8], ttl: u64) -> Result<(), KvError> {
+    let mut pair = KVPair::new(*key, value.to_vec());
+    pair.ttl = Some(ttl);
+    let msg = KVMsg::new(KVMsgType::Set, pair.to_bytes());
+    set_reply(&kvc_request(connection, msg)?)
 }
 
-pub fn kvc_set(connection: &mut KVConnection, key: &KVKey, value: &Vec<u8>) -> Result<Vec<u8>, Errno> {
+/// Store value at key only if the key is still at version, as returned by
+/// kvc_get_versioned, or 0 if the key must not be present. Returns the new version, or
+/// None if the key was changed by someone else and nothing was written
+pub fn kvc_cas(connection: &mut KVConnection, key: &KVKey, value: &[u8], version: u64) -> Result<Option<u64>, KvError> {
+    if connection.version < GET_VERSION_SINCE {
+        return Err(KvError::Protocol("server does not report versions"));
+    }
+    let mut pair = KVPair::new(*key, value.to_vec());
+    pair.cas = Some(version);
+    let response = kvc_request(connection, KVMsg::new(KVMsgType::CompareAndSet, pair.to_bytes()))?;
+    match response.reply_status()? {
+        (KVStatus::Ok, payload) => match payload.try_into() {
+            Ok(version) => Ok(Some(u64::from_le_bytes(version))),
+            Err(_) => Err(KvError::Protocol("compare and set reply without its version")),
+        },
+        (KVStatus::Conflict, _) => Ok(None),
+        (status, _) => Err(KvError::Status(status)),
+    }
+}
 
-    /*todo: define a KVPAIR struct for serialization / deserialization? */
-    let mut bytes: Vec<u8> = Vec::new();
-    bytes.extend(key.to_bytes());
-    bytes.extend(value);
-    let msg = KVMsg::new(KVMsgType::Set, bytes);
+fn incr_reply(response: &KVMsg) -> Result<i64, KvError> {
+    match response.reply_status()? {
+        (KVStatus::Ok, payload) => match payload.try_into() {
+            Ok(value) => Ok(i64::from_le_bytes(value)),
+            Err(_) => Err(KvError::Protocol("increment reply without its value")),
+        },
+        (status, _) => Err(KvError::Status(status)),
+    }
+}
 
-    match connection.send_kvmsg(msg){
-        Ok(y) => y,
-        Err(e) => return Err(e),
-    };
-    let response = connection.recv_kvmsg().unwrap();
+/// Atomically add delta to the integer stored at key, a missing key starting from 0.
+/// Returns the new value
+pub fn kvc_incr(connection: &mut KVConnection, key: &KVKey, delta: i64) -> Result<i64, KvError> {
+    let body = KVIncr { key: *key, delta }.to_bytes();
+    incr_reply(&kvc_request(connection, KVMsg::new(KVMsgType::Incr, body))?)
+}
 
-    /* todo: do something more specific here... */
-    Ok(response.msg)
+/// Atomically subtract delta from the integer stored at key, a missing key starting from 0.
+/// Returns the new value
+pub fn kvc_decr(connection: &mut KVConnection, key: &KVKey, delta: i64) -> Result<i64, KvError> {
+    let body = KVIncr { key: *key, delta }.to_bytes();
+    incr_reply(&kvc_request(connection, KVMsg::new(KVMsgType::Decr, body))?)
 }
 
-pub fn kvc_delete(connection: &mut KVConnection, key: &KVKey) -> Result<Vec<u8>, Errno>{
+/// Delete key, returns false if the key was not present
+pub fn kvc_delete(connection: &mut KVConnection, key: &KVKey) -> Result<bool, KvError>{
     let msg = KVMsg::new(KVMsgType::Delete, key.to_bytes());
+    found_reply(&kvc_request(connection, msg)?)
+}
+
+/// Whether key is present, without transferring its value
+pub fn kvc_exists(connection: &mut KVConnection, key: &KVKey) -> Result<bool, KvError> {
+    let msg = KVMsg::new(KVMsgType::Exists, key.to_bytes());
+    found_reply(&kvc_request(connection, msg)?)
+}
+
+fn u64_reply(response: &KVMsg) -> Result<Option<u64>, KvError> {
+    match response.reply_status()? {
+        (KVStatus::Ok, payload) => match payload.try_into() {
+            Ok(value) => Ok(Some(u64::from_le_bytes(value))),
+            Err(_) => Err(KvError::Protocol("reply without its u64 payload")),
+        },
+        (KVStatus::NotFound, _) => Ok(None),
+        (status, _) => Err(KvError::Status(status)),
+    }
+}
+
+/// Number of keys beginning with prefix, every key if prefix is None
+pub fn kvc_count(connection: &mut KVConnection, prefix: Option<&KVKey>) -> Result<u64, KvError> {
+    let prefix = match prefix {
+        Some(prefix) => *prefix,
+        None => KVKey::from_slice(&[])?,
+    };
+    let response = kvc_request(connection, KVMsg::new(KVMsgType::Count, prefix.to_bytes()))?;
+    u64_reply(&response)?.ok_or(KvError::Status(KVStatus::NotFound))
+}
+
+/// Length of the value stored at key without transferring it, None if the key is not present
+pub fn kvc_strlen(connection: &mut KVConnection, key: &KVKey) -> Result<Option<u64>, KvError> {
+    let msg = KVMsg::new(KVMsgType::StrLen, key.to_bytes());
+    u64_reply(&kvc_request(connection, msg)?)
+}
+
+/// Per-key statuses of a multi-key reply, checked to hold one result per key sent
+fn multi_reply(response: &KVMsg, count: usize) -> Result<Vec<(KVStatus, Vec<u8>)>, KvError> {
+    match response.reply_status()? {
+        (KVStatus::Ok, payload) => {
+            let list = KVResultList::from_bytes(payload)?;
+            if list.results.len() != count {
+                return Err(KvError::Protocol("reply holds a different number of results than keys sent"));
+            }
+            Ok(list.results)
+        },
+        (status, _) => Err(KvError::Status(status)),
+    }
+}
+
+/// Outcome of one key of kvc_mget, as kvc_get would return it
+pub type GetResult = Result<Option<Vec<u8>>, KvError>;
 
-    match connection.send_kvmsg(msg){
-        Ok(y) => y,
-        Err(e) => return Err(e),
+/// Get the values of many keys in one round trip, results are in the order of keys.
+/// The outer error means the whole request failed
+pub fn kvc_mget(connection: &mut KVConnection, keys: &[KVKey]) -> Result<Vec<GetResult>, KvError> {
+    let list = KVKeyList { keys: keys.to_vec() };
+    let response = kvc_request(connection, KVMsg::new(KVMsgType::MultiGet, list.to_bytes()))?;
+    Ok(multi_reply(&response, keys.len())?.into_iter().map(|result| match result {
+        (KVStatus::Ok, value) => Ok(Some(value)),
+        (KVStatus::NotFound, _) => Ok(None),
+        (status, _) => Err(KvError::Status(status)),
+    }).collect())
+}
+
+/// Store many key value pairs in one round trip, results are in the order of pairs.
+/// The outer error means the whole request failed
+pub fn kvc_mset(connection: &mut KVConnection, pairs: &[(&KVKey, &[u8])]) -> Result<Vec<Result<(), KvError>>, KvError> {
+    let list = KVPairList {
+        pairs: pairs.iter().map(|(key, value)| KVPair::new(**key, value.to_vec())).collect(),
     };
-    let response = connection.recv_kvmsg().unwrap();
+    let response = kvc_request(connection, KVMsg::new(KVMsgType::MultiSet, list.to_bytes()))?;
+    Ok(multi_reply(&response, pairs.len())?.into_iter().map(|result| match result {
+        (KVStatus::Ok, _) => Ok(()),
+        (status, _) => Err(KvError::Status(status)),
+    }).collect())
+}
+
+/// Delete many keys in one round trip, for each key whether it was present.
+/// The outer error means the whole request failed
+pub fn kvc_mdelete(connection: &mut KVConnection, keys: &[KVKey]) -> Result<Vec<Result<bool, KvError>>, KvError> {
+    let list = KVKeyList { keys: keys.to_vec() };
+    let response = kvc_request(connection, KVMsg::new(KVMsgType::MultiDelete, list.to_bytes()))?;
+    Ok(multi_reply(&response, keys.len())?.into_iter().map(|result| match result {
+        (KVStatus::Ok, _) => Ok(true),
+        (KVStatus::NotFound, _) => Ok(false),
+        (status, _) => Err(KvError::Status(status)),
+    }).collect())
+}
+
+/// Requests a pipeline keeps in flight before it waits on a reply
+const PIPELINE_WINDOW: usize = 64;
+/// Request bytes a pipeline keeps in flight before it waits on a reply. The server may be
+/// blocked sending an earlier reply, so requests it has not answered sit in the socket
+/// buffer; while they fit there the client never blocks in send and always gets back to
+/// reading. A larger request is only sent once every earlier reply has arrived
+const PIPELINE_WINDOW_BYTES: usize = 64 << 10;
+
+/// Outcome of one request in a pipeline, in the form kvc_get, kvc_set and kvc_delete return
+#[derive(Debug, PartialEq, Eq)]
+pub enum PipelineReply {
+    Get(Option<Vec<u8>>),
+    Set,
+    Delete(bool),
+}
+
+/// A batch of requests sent without waiting for each reply, see kvc_pipeline
+pub struct Pipeline<'a> {
+    connection: &'a mut KVConnection,
+    requests: Vec<KVMsg>,
+}
 
-    Ok(response.msg)
-}
\ No newline at end of file
+impl Pipeline<'_> {
+    pub fn get(mut self, key: &KVKey) -> Self {
+        self.requests.push(KVMsg::new(KVMsgType::Get, key.to_bytes()));
+        self
+    }
+
+    pub fn set(mut self, key: &KVKey, value: &[u8]) -> Self {
+        let pair = KVPair::new(*key, value.to_vec());
+        self.requests.push(KVMsg::new(KVMsgType::Set, pair.to_bytes()));
+        self
+    }
+
+    pub fn del(mut self, key: &KVKey) -> Self {
+        self.requests.push(KVMsg::new(KVMsgType::Delete, key.to_bytes()));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Send every request and collect the replies, matched to their requests by id.
+    /// Results are in the order the requests were added; the outer error means the
+    /// connection failed and the outcome of requests without a reply is unknown.
+    pub fn exec(self) -> Result<Vec<Result<PipelineReply, KvError>>, KvError> {
+        /* a server without pipelining gets one request at a time, as do replies without ids */
+        let pipelining = self.connection.features & FEATURE_PIPELINING != 0 && self.connection.version >= REQID_SINCE;
+        let window = if pipelining { PIPELINE_WINDOW } else { 1 };
+
+        let count = self.requests.len();
+        let mut pending: HashMap<u64, (usize, KVMsgType, usize)> = HashMap::with_capacity(window);
+        let mut pending_bytes: usize = 0;
+        let mut results: Vec<Option<Result<PipelineReply, KvError>>> = (0..count).map(|_| None).collect();
+        let mut requests = self.requests.into_iter().enumerate().peekable();
+        let mut received = 0;
+
+        while received < count {
+            /* keep the window full, then wait for the oldest outstanding reply */
+            while pending.len() < window {
+                let Some((_, msg)) = requests.peek() else { break };
+                let bytes = 8 + KVMsg::header_len(self.connection.version) + msg.msg.len();
+                if !pending.is_empty() && pending_bytes + bytes > PIPELINE_WINDOW_BYTES {
+                    break;
+                }
+                let (i, msg) = requests.next().unwrap();
+                let msgtype = msg.msgtype;
+                let reqid = self.connection.send_request(msg)?;
+                pending.insert(reqid, (i, msgtype, bytes));
+                pending_bytes += bytes;
+            }
+
+            let response = self.connection.recv_kvmsg()?;
+            mark_received();
+            let Some((i, msgtype, bytes)) = pending.remove(&response.reqid) else {
+                return Err(unmatched_reply(&response));
+            };
+            pending_bytes -= bytes;
+            results[i] = Some(match msgtype {
+                KVMsgType::Get => get_reply(&response, self.connection.version)
+                    .map(|found| PipelineReply::Get(found.map(|(value, _)| value))),
+                KVMsgType::Set => set_reply(&response).map(|()| PipelineReply::Set),
+                _ => found_reply(&response).map(PipelineReply::Delete),
+            });
+            received += 1;
+        }
+
+        Ok(results.into_iter().map(|r| r.unwrap()).collect())
+    }
+}
+
+/// Start a batch of requests on connection, e.g.
+/// kvc_pipeline(&mut connection).get(&k1).set(&k2, v).exec()
+pub fn kvc_pipeline(connection: &mut KVConnection) -> Pipeline<'_> {
+    Pipeline {
+        connection,
+        requests: Vec::new(),
+    }
+}
+
+/// Writes applied all together or not at all, see kvc_transaction
+pub struct Transaction<'a> {
+    connection: &'a mut KVConnection,
+    txn: KVTxn,
+}
+
+impl Transaction<'_> {
+    /// Only commit if key is at version, 0 meaning the key must not be present
+    pub fn guard(mut self, key: &KVKey, version: u64) -> Self {
+        self.txn.guards.push((*key, version));
+        self
+    }
+
+    pub fn set(mut self, key: &KVKey, value: &[u8]) -> Self {
+        self.txn.ops.push(KVTxnOp::Set(KVPair::new(*key, value.to_vec())));
+        self
+    }
+
+    pub fn del(mut self, key: &KVKey) -> Self {
+        self.txn.ops.push(KVTxnOp::Delete(*key));
+        self
+    }
+
+    /// Commit the writes, returns the version they were given.
+    /// A guard that did not hold is KvError::Status(KVStatus::Conflict), and nothing was written
+    pub fn exec(self) -> Result<u64, KvError> {
+        let response = kvc_request(self.connection, KVMsg::new(KVMsgType::Transaction, self.txn.to_bytes()))?;
+        match response.reply_status()? {
+            (KVStatus::Ok, payload) => match payload.try_into() {
+                Ok(version) => Ok(u64::from_le_bytes(version)),
+                Err(_) => Err(KvError::Protocol("transaction reply without its version")),
+            },
+            (status, _) => Err(KvError::Status(status)),
+        }
+    }
+}
+
+/// Start a transaction on connection, e.g.
+/// kvc_transaction(&mut connection).guard(&k1, 0).set(&k1, v).del(&k2).exec()
+pub fn kvc_transaction(connection: &mut KVConnection) -> Transaction<'_> {
+    Transaction {
+        connection,
+        txn: KVTxn::default(),
+    }
+}
+
+/// What a ScanIter walks over
+enum ScanRange {
+    /// keys before end, or all remaining keys
+    Keys { end: Option<KVKey> },
+    /// keys beginning with prefix
+    Prefix { prefix: KVKey },
+}
+
+/// Pairs of a scan in key order, fetched from the server a page at a time as the
+/// iterator advances, see kvc_scan and kvc_prefix_scan.
+/// Stops after the first error
+pub struct ScanIter<'a> {
+    connection: &'a mut KVConnection,
+    range: ScanRange,
+    /// key the next page starts at, None once the server sent the last page
+    cursor: Option<KVKey>,
+    first: bool,
+    page: std::vec::IntoIter<(KVKey, Vec<u8>)>,
+    page_size: u32,
+}
+
+impl ScanIter<'_> {
+    /// Ask for at most page_size pairs per round trip, 0 leaves it to the server
+    pub fn page_size(mut self, page_size: u32) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    fn fetch(&mut self) -> Result<(), KvError> {
+        let start = self.cursor.take().expect("ScanIter::fetch: no cursor");
+        let msg = match &self.range {
+            ScanRange::Keys { end } => {
+                let scan = KVScan { start, end: *end, limit: self.page_size };
+                KVMsg::new(KVMsgType::Scan, scan.to_bytes())
+            },
+            ScanRange::Prefix { prefix } => {
+                let cursor = if self.first { None } else { Some(start) };
+                let scan = KVPrefixScan { prefix: *prefix, cursor, limit: self.page_size };
+                KVMsg::new(KVMsgType::PrefixScan, scan.to_bytes())
+            },
+        };
+        self.first = false;
+
+        let response = kvc_request(self.connection, msg)?;
+        match response.reply_status()? {
+            (KVStatus::Ok, payload) => {
+                let page = KVScanPage::from_bytes(payload)?;
+                /* a cursor must move forward or the scan would never end */
+                if page.cursor.is_some() && page.pairs.last().is_none_or(|(key, _)| page.cursor <= Some(*key)) {
+                    return Err(KvError::Protocol("scan cursor does not move forward"));
+                }
+                self.cursor = page.cursor;
+                self.page = page.pairs.into_iter();
+                Ok(())
+            },
+            (status, _) => Err(KvError::Status(status)),
+        }
+    }
+}
+
+impl Iterator for ScanIter<'_> {
+    type Item = Result<(KVKey, Vec<u8>), KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(pair) = self.page.next() {
+                return Some(Ok(pair));
+            }
+            self.cursor?;
+            if let Err(e) = self.fetch() {
+                self.cursor = None;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// Iterate over the pairs from start in key order, up to but not including end if given.
+/// Nothing is sent until the first call to next
+pub fn kvc_scan<'a>(connection: &'a mut KVConnection, start: &KVKey, end: Option<&KVKey>) -> ScanIter<'a> {
+    ScanIter {
+        connection,
+        range: ScanRange::Keys { end: end.copied() },
+        cursor: Some(*start),
+        first: true,
+        page: Vec::new().into_iter(),
+        page_size: 0,
+    }
+}
+
+/// Iterate over the pairs whose keys begin with prefix in key order, e.g. all the keys
+/// of one user with the prefix "user:123:". Nothing is sent until the first call to next
+pub fn kvc_prefix_scan<'a>(connection: &'a mut KVConnection, prefix: &KVKey) -> ScanIter<'a> {
+    ScanIter {
+        connection,
+        range: ScanRange::Prefix { prefix: *prefix },
+        cursor: Some(*prefix),
+        first: true,
+        page: Vec::new().into_iter(),
+        page_size: 0,
+    }
+}
+
+/// Text forms of binary keys and values for command line use
+pub mod encoding {
+    use kv_shared::io::{KVKey, KvError};
+
+    const B64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    /// Parse a key argument: "hex:<hex digits>", "b64:<base64>" or plain text
+    pub fn parse_key(arg: &str) -> Result<KVKey, KvError> {
+        let bytes = if let Some(hex) = arg.strip_prefix("hex:") {
+            hex_decode(hex)?
+        } else if let Some(b64) = arg.strip_prefix("b64:") {
+            base64_decode(b64)?
+        } else {
+            arg.as_bytes().to_vec()
+        };
+        KVKey::from_slice(&bytes)
+    }
+
+    /// Key as plain text when it is printable utf-8, otherwise in the "hex:" form parse_key reads
+    pub fn format_key(key: &KVKey) -> String {
+        match key.as_str() {
+            Some(s) if !s.chars().any(char::is_control) && !s.starts_with("hex:") && !s.starts_with("b64:") => s.to_string(),
+            _ => format!("hex:{}", hex_encode(key.as_bytes())),
+        }
+    }
+
+    /// s as a quoted JSON string
+    pub fn json_string(s: &str) -> String {
+        let mut json = String::with_capacity(s.len() + 2);
+        json.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => json.push_str("\\\""),
+                '\\' => json.push_str("\\\\"),
+                '\n' => json.push_str("\\n"),
+                '\r' => json.push_str("\\r"),
+                '\t' => json.push_str("\\t"),
+                c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+                c => json.push(c),
+            }
+        }
+        json.push('"');
+        json
+    }
+
+    pub fn hex_encode(bytes: &[u8]) -> String {
+        let mut s = String::with_capacity(bytes.len() * 2);
+        for b in bytes {
+            s.push_str(&format!("{:02x}", b));
+        }
+        s
+    }
+
+    pub fn hex_decode(s: &str) -> Result<Vec<u8>, KvError> {
+        if !s.len().is_multiple_of(2) {
+            return Err(KvError::InvalidInput("hex input has an odd number of digits"));
+        }
+        s.as_bytes()
+            .chunks(2)
+            .map(|pair| {
+                let digits = std::str::from_utf8(pair).map_err(|_| KvError::InvalidInput("bad hex digit"))?;
+                u8::from_str_radix(digits, 16).map_err(|_| KvError::InvalidInput("bad hex digit"))
+            })
+            .collect()
+    }
+
+    pub fn base64_encode(bytes: &[u8]) -> String {
+        let mut s = String::with_capacity(bytes.len().div_ceil(3) * 4);
+        for chunk in bytes.chunks(3) {
+            let n = (chunk[0] as u32) << 16
+                | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+                | *chunk.get(2).unwrap_or(&0) as u32;
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    s.push(B64_ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+                } else {
+                    s.push('=');
+                }
+            }
+        }
+        s
+    }
+
+    pub fn base64_decode(s: &str) -> Result<Vec<u8>, KvError> {
+        let s = s.trim_end_matches('=');
+        let mut bytes: Vec<u8> = Vec::with_capacity(s.len() * 3 / 4);
+        let mut acc: u32 = 0;
+        let mut bits: u32 = 0;
+        for c in s.bytes() {
+            let v = match B64_ALPHABET.iter().position(|&a| a == c) {
+                Some(v) => v as u32,
+                None => return Err(KvError::InvalidInput("bad base64 character")),
+            };
+            acc = acc << 6 | v;
+            bits += 6;
+            if bits >= 8 {
+                bits -= 8;
+                bytes.push((acc >> bits) as u8);
+                acc &= (1 << bits) - 1;
+            }
+        }
+        Ok(bytes)
+    }
+}
@@ -0,0 +1,119 @@
+//! Write throughput of log_set under Durability::Always as the number of writer threads grows,
+//! against a naive fsync on every write.
+//!
+//! cargo run --release -p kv-server --example group_commit [writes_per_thread]
+//!
+//! With one writer every write pays for its own fsync. The naive mode fsyncs under the
+//! store lock on every write, so that is also its ceiling for any number of writers.
+//! With group commit one fsync covers every write appended while the previous one was
+//! running, so throughput should climb with the writer count. Durability::Never is shown
+//! for reference.
+//!
+//! writes/s with the default 500 x 100-byte writes per writer, release build, ext4, 1 CPU:
+//!
+//!   writers      naive     always      never
+//!         1      17131      17706     708524
+//!         2      17007      20705     720920
+//!         5      17466      42629     709014
+//!        16      17220      84769     688984
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use kv_server::log_set;
+use kv_server::storage::{Durability, LogStore, StoreConfig, segment_path};
+use kv_shared::io::KVKey;
+
+const WRITER_COUNTS: [usize; 4] = [1, 2, 5, 16];
+const VALUE_LEN: usize = 100;
+
+#[derive(Clone, Copy)]
+enum Mode {
+    /// full fsync under the store lock after every write, no group commit
+    Naive,
+    /// log_set under Durability::Always
+    Always,
+    /// log_set under Durability::Never
+    Never,
+}
+
+impl Mode {
+    fn name(&self) -> &'static str {
+        match self {
+            Mode::Naive => "naive",
+            Mode::Always => "always",
+            Mode::Never => "never",
+        }
+    }
+}
+
+/// The only segment of a fresh store, writes here never fill it enough to roll
+fn active_segment(dir: &Path) -> File {
+    File::open(segment_path(dir, 1)).expect("open segment failed")
+}
+
+/// LogStore is shared between writers the same way the server shares it between workers
+struct StorePtr(*mut LogStore);
+unsafe impl Send for StorePtr {}
+unsafe impl Sync for StorePtr {}
+
+fn run(mode: Mode, writers: usize, writes_per_thread: usize) -> Duration {
+    let dir = PathBuf::from(format!("./group-commit-bench-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut store = LogStore::open(StoreConfig {
+        dir: dir.clone(),
+        durability: match mode {
+            Mode::Always => Durability::Always,
+            Mode::Naive | Mode::Never => Durability::Never,
+        },
+        ..Default::default()
+    }).expect("LogStore::open failed");
+    let segment = active_segment(&dir);
+
+    let ptr = StorePtr(&mut store);
+    let value = vec![b'x'; VALUE_LEN];
+    let start = Instant::now();
+    std::thread::scope(|scope| {
+        for w in 0..writers {
+            let ptr = &ptr;
+            let value = &value;
+            let segment = &segment;
+            scope.spawn(move || {
+                let store = unsafe { &mut *ptr.0 };
+                for i in 0..writes_per_thread {
+                    let key = KVKey::new(&format!("w{}:{}", w, i)).unwrap();
+                    match mode {
+                        Mode::Naive => {
+                            store.lock().unwrap();
+                            store.set(&key, value, 0).expect("set failed");
+                            segment.sync_all().expect("fsync failed");
+                            store.unlock().unwrap();
+                        }
+                        Mode::Always | Mode::Never => log_set(store, &key, value, 0).expect("log_set failed"),
+                    }
+                }
+            });
+        }
+    });
+    let elapsed = start.elapsed();
+
+    drop(store);
+    let _ = std::fs::remove_dir_all(&dir);
+    elapsed
+}
+
+fn main() {
+    let writes_per_thread: usize = std::env::args()
+        .nth(1)
+        .map(|s| s.parse().expect("writes_per_thread must be a number"))
+        .unwrap_or(500);
+
+    println!("{:<8} {:>8} {:>10} {:>12}", "mode", "writers", "writes", "writes/s");
+    for mode in [Mode::Naive, Mode::Always, Mode::Never] {
+        for writers in WRITER_COUNTS {
+            let writes = writers * writes_per_thread;
+            let elapsed = run(mode, writers, writes_per_thread);
+            println!("{:<8} {:>8} {:>10} {:>12.0}", mode.name(), writers, writes, writes as f64 / elapsed.as_secs_f64());
+        }
+    }
+}
@@ -1,49 +1,1672 @@
 use std::{os::{fd::{AsRawFd, FromRawFd, OwnedFd, RawFd}}, path::Path};
 use nix::{errno::Errno, sys::socket::{AddressFamily, Backlog, SockFlag, SockType, UnixAddr, accept, bind, listen, socket}, unistd::unlink};
-use kv_shared::{ringbuffer::FdRingBuffer};
+use kv_shared::{io::{KVKey, KvError}, ringbuffer::FdRingBuffer};
 
-/// Get value from log
-pub fn log_get(){}
+use crate::storage::{IncrResult, LogStore, ScanPage, TxnOp};
 
-/// Set key value pair in log
-pub fn log_set(){}
+/// println! when the log level is info or more verbose
+#[macro_export]
+macro_rules! kv_info {
+    ($($arg:tt)*) => {
+        if $crate::logging::log_enabled($crate::logging::LogLevel::Info) {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// println! when the log level is debug
+#[macro_export]
+macro_rules! kv_debug {
+    ($($arg:tt)*) => {
+        if $crate::logging::log_enabled($crate::logging::LogLevel::Debug) {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Get value and its version from log
+pub fn log_get(store: &mut LogStore, key: &KVKey) -> Result<Option<(Vec<u8>, u64)>, KvError>{
+    store.lock()?;
+    let result = store.get(key);
+    store.unlock()?;
+    result.map_err(KvError::Io)
+}
+
+/// Set key value pair in log until expires, unix time in milliseconds or 0 for never.
+/// Returns once the write is durable
+pub fn log_set(store: &mut LogStore, key: &KVKey, value: &[u8], expires: u64) -> Result<(), KvError>{
+    store.lock()?;
+    let result = store.set(key, value, expires).and_then(|_| store.sync());
+    store.unlock()?;
+    result.map_err(KvError::Io)
+}
+
+/// Set key value pair in log only if the key is at version, 0 meaning not present.
+/// Returns the new version, or None if the version did not match. Returns once the write is durable
+pub fn log_cas(store: &mut LogStore, key: &KVKey, value: &[u8], expires: u64, version: u64) -> Result<Option<u64>, KvError>{
+    store.lock()?;
+    let result = store.compare_and_set(key, value, expires, version).and_then(|version| match version {
+        Some(_) => store.sync().map(|_| version),
+        None => Ok(None),
+    });
+    store.unlock()?;
+    result.map_err(KvError::Io)
+}
+
+/// Add delta to the integer at key, returns once the write is durable
+pub fn log_incr(store: &mut LogStore, key: &KVKey, delta: i64) -> Result<IncrResult, KvError>{
+    store.lock()?;
+    let result = store.incr(key, delta).and_then(|result| match result {
+        IncrResult::Value(_) => store.sync().map(|_| result),
+        _ => Ok(result),
+    });
+    store.unlock()?;
+    result.map_err(KvError::Io)
+}
+
+/// Delete key value pair from log, returns false if the key was not present.
+/// Returns once the delete is durable
+pub fn log_del(store: &mut LogStore, key: &KVKey) -> Result<bool, KvError>{
+    store.lock()?;
+    let result = store.delete(key).and_then(|deleted| store.sync().map(|_| deleted));
+    store.unlock()?;
+    result.map_err(KvError::Io)
+}
+
+/// Get the values of many keys under one lock, in the order of keys
+pub fn log_mget(store: &mut LogStore, keys: &[KVKey]) -> Result<Vec<Option<Vec<u8>>>, KvError>{
+    store.lock()?;
+    let result = keys.iter()
+        .map(|key| store.get(key).map(|found| found.map(|(value, _)| value)))
+        .collect::<Result<Vec<_>, _>>();
+    store.unlock()?;
+    result.map_err(KvError::Io)
+}
+
+/// Set many key value pairs, each with its expiry time, under one lock, returns once all
+/// of them are durable. Stops at the first failed write, the pairs before it may be stored
+pub fn log_mset(store: &mut LogStore, pairs: &[(&KVKey, &[u8], u64)]) -> Result<(), KvError>{
+    store.lock()?;
+    let result = pairs.iter()
+        .try_for_each(|(key, value, expires)| store.set(key, value, *expires).map(|_| ()))
+        .and_then(|_| store.sync());
+    store.unlock()?;
+    result.map_err(KvError::Io)
+}
+
+/// Delete many keys under one lock, returns for each key whether it was present.
+/// Returns once the deletes are durable
+pub fn log_mdel(store: &mut LogStore, keys: &[KVKey]) -> Result<Vec<bool>, KvError>{
+    store.lock()?;
+    let result = keys.iter()
+        .map(|key| store.delete(key))
+        .collect::<Result<Vec<bool>, _>>()
+        .and_then(|deleted| store.sync().map(|_| deleted));
+    store.unlock()?;
+    result.map_err(KvError::Io)
+}
+
+/// Apply ops all together if every guard holds, returns the version given to the writes
+/// or None if a guard failed and nothing was written. Returns once the writes are durable
+pub fn log_commit(store: &mut LogStore, guards: &[(KVKey, u64)], ops: &[TxnOp]) -> Result<Option<u64>, KvError>{
+    store.lock()?;
+    let result = store.commit(guards, ops).and_then(|version| match version {
+        Some(_) => store.sync().map(|_| version),
+        None => Ok(None),
+    });
+    store.unlock()?;
+    result.map_err(KvError::Io)
+}
+
+/// Whether key is present in log
+pub fn log_exists(store: &mut LogStore, key: &KVKey) -> Result<bool, KvError>{
+    store.lock()?;
+    let exists = store.exists(key);
+    store.unlock()?;
+    Ok(exists)
+}
+
+/// Length of the value of key in log
+pub fn log_strlen(store: &mut LogStore, key: &KVKey) -> Result<Option<u64>, KvError>{
+    store.lock()?;
+    let len = store.value_len(key);
+    store.unlock()?;
+    Ok(len)
+}
+
+/// Number of live keys in log beginning with prefix, all of them for an empty prefix
+pub fn log_count(store: &mut LogStore, prefix: &[u8]) -> Result<usize, KvError>{
+    store.lock()?;
+    let count = store.count(prefix);
+    store.unlock()?;
+    Ok(count)
+}
+
+/// Scan pairs in key order from start, see LogStore::scan
+pub fn log_scan(store: &mut LogStore, start: &KVKey, end: Option<&KVKey>, prefix: &[u8], limit: usize, max_bytes: usize) -> Result<ScanPage, KvError>{
+    store.lock()?;
+    let result = store.scan(start, end, prefix, limit, max_bytes);
+    store.unlock()?;
+    result.map_err(KvError::Io)
+}
+
+/// Open unix tcp socket, bind and listen
+pub fn open_socket(path: &Path) -> Result<OwnedFd, Errno>{
+    let socket_addr = UnixAddr::new(path).expect("open_socket: UnixAddr failed");
+
+    match unlink(path){
+        Ok(_) => (),
+        Err(Errno::ENOENT) => (), /* .sock already exists, continue */
+        Err(e) => {
+            eprintln!("open_socket: unlink socket_path: {}", e);
+            return Err(e);
+        }
+    };
+
+    let sockfd = socket(
+        AddressFamily::Unix, 
+        SockType::Stream,
+        SockFlag::empty(),
+        None,
+    ).expect("open_socket: socket failed");
+
+    bind(sockfd.as_raw_fd(), &socket_addr).expect("open_socket: bind failed");
+    listen(&sockfd, Backlog::MAXCONN).expect("open_socket: listen failed");
+
+    Ok(sockfd)
+}
+
+pub fn accept_connection(socket_fd: &OwnedFd, rbuf: &mut FdRingBuffer) -> Result<(), Errno>{
+    /* todo: write connectionfd into a buffer */
+
+    let connfd_raw: RawFd = accept(socket_fd.as_raw_fd()).expect("accept failed");
+    let connfd = unsafe { OwnedFd::from_raw_fd(connfd_raw) };
+    rbuf.put(connfd).expect("FdRingBuffer full or bad put");
+    Ok(())
+}
+
+pub mod storage {
+    use std::{collections::{BTreeMap, BTreeSet, HashMap}, ffi::OsString, ops::{Bound, Range}, os::fd::OwnedFd, path::{Path, PathBuf}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+    use kv_shared::{io::KVKey, semaphores::{kv_cond_broadcast, kv_cond_init, kv_cond_wait, kv_mutex_init, kv_mutex_lock, kv_mutex_unlock}};
+    use nix::{errno::Errno, fcntl::{AT_FDCWD, OFlag, open, renameat}, libc::{pthread_cond_t, pthread_mutex_t}, sys::{stat::{Mode, fstat}, uio::{pread, pwrite}}, unistd::{dup, fsync, ftruncate, mkdir, unlink}};
+
+    /* record layout, integers little endian:
+     *   crc32    u32     checksum of every byte after this field
+     *   flags    u8      RECORD_TOMBSTONE, RECORD_VERSIONED, RECORD_BATCH, RECORD_EXPIRES
+     *   key_len  u32
+     *   val_len  u64
+     *   version  u64     only with RECORD_VERSIONED, records written before versions lack it
+     *   expires  u64     only with RECORD_EXPIRES, unix time in milliseconds
+     *   key      key_len bytes
+     *   value    val_len bytes
+     *
+     * a RECORD_BATCH record has no key, its value is the Sets and Deletes of one
+     * transaction, all sharing the record's version:
+     *   flags    u8      RECORD_TOMBSTONE, RECORD_EXPIRES
+     *   key_len  u32
+     *   val_len  u64
+     *   expires  u64     only with RECORD_EXPIRES
+     *   key      key_len bytes
+     *   value    val_len bytes
+     */
+    pub const RECORD_HEADER_LEN: usize = 17;
+    pub const RECORD_VERSION_LEN: usize = 8;
+    pub const BATCH_OP_HEADER_LEN: usize = 13;
+    pub const RECORD_TOMBSTONE: u8 = 0x01;
+    pub const RECORD_VERSIONED: u8 = 0x02;
+    pub const RECORD_BATCH: u8 = 0x04;
+    pub const RECORD_EXPIRES: u8 = 0x08;
+
+    pub const SEGMENT_EXT: &str = "kvlog";
+    pub const HINT_EXT: &str = "kvhint";
+
+    /* hint file layout, one per sealed segment, integers little endian:
+     *   seg_len  u64     length of the segment the hints describe
+     *   count    u64
+     *   count entries of:
+     *     flags    u8    RECORD_TOMBSTONE, RECORD_VERSIONED, RECORD_EXPIRES
+     *     key_len  u32
+     *     segment  u32
+     *     offset   u64   offset of the value in the segment
+     *     val_len  u64
+     *     version  u64   only with RECORD_VERSIONED
+     *     expires  u64   only with RECORD_EXPIRES
+     *     key      key_len bytes
+     *   crc32    u32     checksum of every byte before this field
+     */
+    const HINT_HEADER_LEN: usize = 16;
+    const HINT_ENTRY_LEN: usize = 25;
+    /// Roll over to a new segment once the active one would pass this size
+    pub const DEFAULT_SEGMENT_MAX_BYTES: u64 = 64 << 20;
+
+    /// Logs smaller than this are never compacted
+    pub const COMPACT_MIN_BYTES: u64 = 1 << 20;
+    /// Compact once at least this fraction of the log is overwritten or deleted records
+    pub const COMPACT_STALE_RATIO: f64 = 0.5;
+    /// Most expired keys dropped under one hold of the store lock
+    const EXPIRE_BATCH: usize = 1024;
+
+    /// Current unix time in milliseconds
+    pub fn now_millis() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.as_millis() as u64)
+    }
+
+    /// Expiry time for a value set now with a ttl in seconds, 0 for no expiry
+    pub fn expires_after(ttl: Option<u64>) -> u64 {
+        match ttl {
+            Some(ttl) => now_millis().saturating_add(ttl.saturating_mul(1000)),
+            None => 0,
+        }
+    }
+
+    /* bytes the optional expires field takes */
+    fn expires_len(expires: u64) -> usize {
+        if expires != 0 { 8 } else { 0 }
+    }
+
+    /// A single Set or Delete as it is written to the data log
+    pub struct LogRecord {
+        pub tombstone: bool,
+        pub version: u64,
+        /// unix time in milliseconds the value expires at, 0 for never
+        pub expires: u64,
+        pub key: Vec<u8>,
+        pub value: Vec<u8>,
+    }
+
+    impl LogRecord {
+        pub fn to_bytes(&self) -> Vec<u8> {
+            let mut flags: u8 = RECORD_VERSIONED;
+            if self.tombstone { flags |= RECORD_TOMBSTONE; }
+            if self.expires != 0 { flags |= RECORD_EXPIRES; }
+            let mut bytes: Vec<u8> = Vec::with_capacity(self.value_offset() as usize + self.value.len());
+            bytes.extend(&0u32.to_le_bytes());                      // 4 bytes, crc placeholder
+            bytes.push(flags);                                      // 1 byte
+            bytes.extend(&(self.key.len() as u32).to_le_bytes());   // 4 bytes
+            bytes.extend(&(self.value.len() as u64).to_le_bytes()); // 8 bytes
+            bytes.extend(&self.version.to_le_bytes());              // 8 bytes
+            if self.expires != 0 {
+                bytes.extend(&self.expires.to_le_bytes());          // 8 bytes
+            }
+            bytes.extend(&self.key);
+            bytes.extend(&self.value);
+            let crc = crc32(&bytes[4..]);
+            bytes[0..4].copy_from_slice(&crc.to_le_bytes());
+            bytes
+        }
+
+        /// Offset of the value from the start of the record
+        fn value_offset(&self) -> u64 {
+            (RECORD_HEADER_LEN + RECORD_VERSION_LEN + expires_len(self.expires) + self.key.len()) as u64
+        }
+
+        /// Decode a record header, returns (crc, flags, key_len, val_len)
+        fn parse_header(header: &[u8; RECORD_HEADER_LEN]) -> (u32, u8, usize, usize) {
+            let crc = u32::from_le_bytes(header[0..4].try_into().unwrap());
+            let flags = header[4];
+            let key_len = u32::from_le_bytes(header[5..9].try_into().unwrap()) as usize;
+            let val_len = u64::from_le_bytes(header[9..17].try_into().unwrap()) as usize;
+            (crc, flags, key_len, val_len)
+        }
+    }
+
+    /// The Sets and Deletes of one transaction, written as a single record so that
+    /// recovery sees either all of them or, if the record is torn, none
+    pub struct LogBatch<'a> {
+        pub version: u64,
+        pub ops: &'a [TxnOp<'a>],
+    }
+
+    impl LogBatch<'_> {
+        pub fn to_bytes(&self) -> Vec<u8> {
+            let mut body: Vec<u8> = Vec::new();
+            for op in self.ops {
+                let (flags, key, value, expires): (u8, &KVKey, &[u8], u64) = match op {
+                    TxnOp::Set(key, value, 0) => (0, key, value, 0),
+                    TxnOp::Set(key, value, expires) => (RECORD_EXPIRES, key, value, *expires),
+                    TxnOp::Delete(key) => (RECORD_TOMBSTONE, key, &[], 0),
+                };
+                body.push(flags);                                       // 1 byte
+                body.extend(&(key.as_bytes().len() as u32).to_le_bytes()); // 4 bytes
+                body.extend(&(value.len() as u64).to_le_bytes());       // 8 bytes
+                if expires != 0 {
+                    body.extend(&expires.to_le_bytes());                // 8 bytes
+                }
+                body.extend(key.as_bytes());
+                body.extend(value);
+            }
+
+            let mut bytes: Vec<u8> = Vec::with_capacity(RECORD_HEADER_LEN + RECORD_VERSION_LEN + body.len());
+            bytes.extend(&0u32.to_le_bytes());                      // 4 bytes, crc placeholder
+            bytes.push(RECORD_BATCH | RECORD_VERSIONED);            // 1 byte
+            bytes.extend(&0u32.to_le_bytes());                      // 4 bytes, no key
+            bytes.extend(&(body.len() as u64).to_le_bytes());       // 8 bytes
+            bytes.extend(&self.version.to_le_bytes());              // 8 bytes
+            bytes.extend(&body);
+            let crc = crc32(&bytes[4..]);
+            bytes[0..4].copy_from_slice(&crc.to_le_bytes());
+            bytes
+        }
+    }
+
+    /// One write of a transaction, a Set carries its expiry time as in LogRecord
+    pub enum TxnOp<'a> {
+        Set(&'a KVKey, &'a [u8], u64),
+        Delete(&'a KVKey),
+    }
+
+    /// Outcome of LogStore::incr, nothing is written unless it is Value
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum IncrResult {
+        /// the value after the increment
+        Value(i64),
+        NotInteger,
+        Overflow,
+    }
+
+    /// Location of a live value inside the data log
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub struct IndexEntry {
+        pub segment: u32,
+        pub offset: u64,
+        pub len: u64,
+        /// version of the write that stored the value
+        pub version: u64,
+        /// unix time in milliseconds the value expires at, 0 for never
+        pub expires: u64,
+    }
+
+    impl IndexEntry {
+        /// Size of the whole record holding this value, once compaction has rewritten it
+        fn record_len(&self, key: &KVKey) -> u64 {
+            (RECORD_HEADER_LEN + RECORD_VERSION_LEN + expires_len(self.expires) + key.as_bytes().len()) as u64 + self.len
+        }
+
+        fn is_expired(&self, now: u64) -> bool {
+            self.expires != 0 && self.expires <= now
+        }
+    }
+
+    /// Index information for one record, the contents of a hint file
+    #[derive(Clone, Copy)]
+    struct HintEntry {
+        key: KVKey,
+        tombstone: bool,
+        offset: u64,
+        len: u64,
+        /// 0 for records written before versions, replay numbers those itself
+        version: u64,
+        expires: u64,
+    }
+
+    /// When a write counts as done
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Durability {
+        /// fsync before every write returns
+        Always,
+        /// a flusher thread fsyncs on this interval, writes wait for the next flush
+        Batched(Duration),
+        /// never fsync, the OS writes pages back on its own schedule
+        Never,
+    }
+
+    /// Storage settings chosen by the server at startup
+    pub struct StoreConfig {
+        pub dir: PathBuf,
+        pub segment_max_bytes: u64,
+        pub durability: Durability,
+    }
+
+    impl Default for StoreConfig {
+        fn default() -> Self {
+            Self {
+                dir: PathBuf::from("./kv-data"),
+                segment_max_bytes: DEFAULT_SEGMENT_MAX_BYTES,
+                durability: Durability::Always,
+            }
+        }
+    }
+
+    /// One numbered file of the data log
+    struct Segment {
+        fd: OwnedFd,
+        len: u64,
+    }
+
+    /// A segment that stopped being the active one, still to be flushed and hinted
+    struct Rolled {
+        id: u32,
+        fd: OwnedFd,
+        len: u64,
+        hints: Vec<HintEntry>,
+        /// writes up to here are durable once fd is flushed
+        seq: u64,
+    }
+
+    /// Sizes of a LogStore, see LogStore::stats
+    #[derive(Clone, Copy, Debug)]
+    pub struct StoreStats {
+        /// keys in the index, including expired ones not yet swept
+        pub keys: usize,
+        /// keys with an expiry time
+        pub expiring: usize,
+        pub segments: usize,
+        /// bytes in all segments
+        pub total_bytes: u64,
+        /// bytes of the records the index points at
+        pub live_bytes: u64,
+        pub last_version: u64,
+        pub compacting: bool,
+    }
+
+    /// One page of a scan and the key the next page starts at
+    pub type ScanPage = (Vec<(KVKey, Vec<u8>)>, Option<KVKey>);
+
+    /// Append-only data log split over numbered segment files, with an in-memory key index.
+    /// Only the highest numbered segment is appended to, the rest are sealed.
+    /// Callers must hold the store lock around get/set/delete.
+    pub struct LogStore {
+        config: StoreConfig,
+        segments: BTreeMap<u32, Segment>,
+        active: u32,
+        active_hints: Vec<HintEntry>,
+        live_bytes: u64,
+        /// live keys in byte order, for point lookups and scans alike
+        index: BTreeMap<KVKey, IndexEntry>,
+        /// keys of the index that expire, soonest first
+        expiring: BTreeSet<(u64, KVKey)>,
+        /// highest version given to a write so far
+        last_version: u64,
+        /// a compaction or a snapshot is running, there is never more than one
+        compacting: bool,
+        write_seq: u64,
+        synced_seq: u64,
+        syncing: bool,
+        mtx: pthread_mutex_t,
+        synced: pthread_cond_t,
+    }
+
+    impl LogStore {
+
+        /// Open or create the segments in config.dir and rebuild the index from their records
+        pub fn open(config: StoreConfig) -> Result<Self, Errno> {
+            match mkdir(&config.dir, Mode::S_IRWXU) {
+                Ok(_) | Err(Errno::EEXIST) => (),
+                Err(e) => {
+                    eprintln!("storage::open: mkdir {}: {}", config.dir.display(), e);
+                    return Err(e);
+                }
+            }
+            let mtx = kv_mutex_init()?;
+            let synced = kv_cond_init()?;
+
+            let mut store = Self {
+                config,
+                segments: BTreeMap::new(),
+                active: 0,
+                active_hints: Vec::new(),
+                live_bytes: 0,
+                index: BTreeMap::new(),
+                expiring: BTreeSet::new(),
+                last_version: 0,
+                compacting: false,
+                write_seq: 0,
+                synced_seq: 0,
+                syncing: false,
+                mtx,
+                synced,
+            };
+            store.recover()?;
+            Ok(store)
+        }
+
+        /// Number of live keys in the index
+        pub fn len(&self) -> usize {
+            self.index.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.index.is_empty()
+        }
+
+        /// Number of segment files, sealed and active
+        pub fn segment_count(&self) -> usize {
+            self.segments.len()
+        }
+
+        pub fn lock(&mut self) -> Result<(), Errno> {
+            kv_mutex_lock(&mut self.mtx)
+        }
+
+        pub fn unlock(&mut self) -> Result<(), Errno> {
+            kv_mutex_unlock(&mut self.mtx)
+        }
+
+        pub fn durability(&self) -> Durability {
+            self.config.durability
+        }
+
+        /// Block until every write made so far is durable under the configured policy.
+        /// Callers must hold the store lock, it is released while waiting for a flush.
+        ///
+        /// With Durability::Always writers commit as a group: the first writer to arrive
+        /// fsyncs on behalf of everything appended so far, writers arriving during that
+        /// fsync wait for it and then one of them leads the next batch.
+        pub fn sync(&mut self) -> Result<(), Errno> {
+            let target = self.write_seq;
+            if self.synced_seq >= target {
+                return Ok(());
+            }
+
+            match self.config.durability {
+                Durability::Always => {
+                    while self.synced_seq < target {
+                        if self.syncing {
+                            kv_cond_wait(&mut self.synced, &mut self.mtx)?;
+                            continue;
+                        }
+
+                        /* lead the batch, appends made while we fsync join the next one */
+                        self.syncing = true;
+                        let batch = self.write_seq;
+                        let result = dup(&self.segments[&self.active].fd).and_then(|fd| {
+                            self.unlock()?;
+                            let result = fsync(&fd);
+                            self.lock()?;
+                            result
+                        });
+                        self.syncing = false;
+                        if let Err(e) = result {
+                            /* wake the followers so one of them retries as leader */
+                            kv_cond_broadcast(&mut self.synced)?;
+                            return Err(e);
+                        }
+                        self.mark_synced(batch)?;
+                    }
+                },
+                Durability::Batched(_) => {
+                    while self.synced_seq < target {
+                        kv_cond_wait(&mut self.synced, &mut self.mtx)?;
+                    }
+                },
+                Durability::Never => (),
+            }
+            Ok(())
+        }
+
+        /// fsync writes made since the last flush and release the writers waiting on them.
+        /// Takes the store lock itself, and does not hold it during the fsync.
+        pub fn flush(&mut self) -> Result<(), Errno> {
+            self.lock()?;
+            /* a segment being sealed holds writes the active segment's fsync would not cover */
+            while self.syncing {
+                if let Err(e) = kv_cond_wait(&mut self.synced, &mut self.mtx) {
+                    self.unlock()?;
+                    return Err(e);
+                }
+            }
+            let target = self.write_seq;
+            if self.synced_seq >= target {
+                return self.unlock();
+            }
+            let fd = match dup(&self.segments[&self.active].fd) {
+                Ok(fd) => fd,
+                Err(e) => {
+                    self.unlock()?;
+                    return Err(e);
+                }
+            };
+            self.unlock()?;
+
+            fsync(&fd)?;
+
+            self.lock()?;
+            let result = self.mark_synced(target);
+            self.unlock()?;
+            result
+        }
+
+        /// Record that writes up to seq are durable, must hold the store lock
+        fn mark_synced(&mut self, seq: u64) -> Result<(), Errno> {
+            if seq > self.synced_seq {
+                self.synced_seq = seq;
+                kv_cond_broadcast(&mut self.synced)?;
+            }
+            Ok(())
+        }
+
+        /// Index entry of key unless it is missing or expired, expired keys are dropped
+        /// from the index here rather than waiting for the next expire()
+        fn live_entry(&mut self, key: &KVKey) -> Option<IndexEntry> {
+            let entry = *self.index.get(key)?;
+            if entry.is_expired(now_millis()) {
+                self.index_remove(key);
+                return None;
+            }
+            Some(entry)
+        }
+
+        fn read_value(&self, entry: &IndexEntry) -> Result<Vec<u8>, Errno> {
+            let mut value = vec![0u8; entry.len as usize];
+            pread_exact(&self.segments[&entry.segment].fd, &mut value, entry.offset)?;
+            Ok(value)
+        }
+
+        /// Value of key and its version
+        pub fn get(&mut self, key: &KVKey) -> Result<Option<(Vec<u8>, u64)>, Errno> {
+            let entry = match self.live_entry(key) {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+            Ok(Some((self.read_value(&entry)?, entry.version)))
+        }
+
+        /// Current version of key, 0 if it is not present or expired
+        pub fn version(&mut self, key: &KVKey) -> u64 {
+            self.live_entry(key).map_or(0, |entry| entry.version)
+        }
+
+        /// Whether key is present, without reading its value
+        pub fn exists(&mut self, key: &KVKey) -> bool {
+            self.live_entry(key).is_some()
+        }
+
+        /// Length of the value at key, without reading it
+        pub fn value_len(&mut self, key: &KVKey) -> Option<u64> {
+            self.live_entry(key).map(|entry| entry.len)
+        }
+
+        /// Number of live keys beginning with prefix, all of them for an empty prefix
+        pub fn count(&self, prefix: &[u8]) -> usize {
+            let now = now_millis();
+            if prefix.is_empty() {
+                /* expired keys still in the index are the first ones of expiring */
+                let expired = self.expiring.iter().take_while(|(expires, _)| *expires <= now).count();
+                return self.index.len() - expired;
+            }
+            let start = KVKey::from_slice(prefix).expect("storage::count: prefix longer than a key");
+            self.index.range(start..)
+                .take_while(|(key, _)| key.as_bytes().starts_with(prefix))
+                .filter(|(_, entry)| !entry.is_expired(now))
+                .count()
+        }
+
+        /// Pairs from start in key order, stopping before end and at the first key without
+        /// prefix. A page holds at most limit pairs and about max_bytes of keys and values,
+        /// but always at least one pair. Returns the page and the key the next page starts
+        /// at, None once there is nothing more to scan
+        pub fn scan(&mut self, start: &KVKey, end: Option<&KVKey>, prefix: &[u8], limit: usize, max_bytes: usize) -> Result<ScanPage, Errno> {
+            let upper = match end {
+                /* range() panics on a reversed range, such a scan is simply empty */
+                Some(end) if end <= start => return Ok((Vec::new(), None)),
+                Some(end) => Bound::Excluded(end),
+                None => Bound::Unbounded,
+            };
+            let now = now_millis();
+            let mut pairs = Vec::new();
+            let mut bytes = 0;
+            let mut cursor = None;
+            let mut expired = Vec::new();
+            for (key, entry) in self.index.range((Bound::Included(start), upper)) {
+                if !key.as_bytes().starts_with(prefix) {
+                    break;
+                }
+                if entry.is_expired(now) {
+                    expired.push(*key);
+                    continue;
+                }
+                let size = key.as_bytes().len() + entry.len as usize;
+                if pairs.len() == limit || (!pairs.is_empty() && bytes + size > max_bytes) {
+                    cursor = Some(*key);
+                    break;
+                }
+                pairs.push((*key, self.read_value(entry)?));
+                bytes += size;
+            }
+            /* same lazy eviction as live_entry, done after the walk over the index */
+            for key in &expired {
+                self.index_remove(key);
+            }
+            Ok((pairs, cursor))
+        }
+
+        /// Store value at key until expires, unix time in milliseconds or 0 for never.
+        /// Returns the version it was given
+        pub fn set(&mut self, key: &KVKey, value: &[u8], expires: u64) -> Result<u64, Errno> {
+            let record = LogRecord {
+                tombstone: false,
+                version: self.last_version + 1,
+                expires,
+                key: key.as_bytes().to_vec(),
+                value: value.to_vec(),
+            };
+            let (segment, offset) = self.append(&record.to_bytes())?;
+            self.last_version = record.version;
+            let hint = HintEntry {
+                key: *key,
+                tombstone: false,
+                offset: offset + record.value_offset(),
+                len: value.len() as u64,
+                version: record.version,
+                expires,
+            };
+            self.apply_hints(segment, &mut [hint]);
+            self.active_hints.push(hint);
+            Ok(record.version)
+        }
+
+        /// Store value at key only if the key is at version, 0 meaning not present.
+        /// Returns the new version, or None without writing if the version did not match
+        pub fn compare_and_set(&mut self, key: &KVKey, value: &[u8], expires: u64, version: u64) -> Result<Option<u64>, Errno> {
+            if self.version(key) != version {
+                return Ok(None);
+            }
+            self.set(key, value, expires).map(Some)
+        }
+
+        /// Add delta to the decimal integer stored at key, a missing key counting as 0.
+        /// The key keeps its expiry time
+        pub fn incr(&mut self, key: &KVKey, delta: i64) -> Result<IncrResult, Errno> {
+            let (current, expires) = match self.live_entry(key) {
+                Some(entry) => {
+                    let value = self.read_value(&entry)?;
+                    match std::str::from_utf8(&value).ok().and_then(|s| s.parse::<i64>().ok()) {
+                        Some(current) => (current, entry.expires),
+                        None => return Ok(IncrResult::NotInteger),
+                    }
+                },
+                None => (0, 0),
+            };
+            let Some(next) = current.checked_add(delta) else {
+                return Ok(IncrResult::Overflow);
+            };
+            self.set(key, next.to_string().as_bytes(), expires)?;
+            Ok(IncrResult::Value(next))
+        }
+
+        pub fn delete(&mut self, key: &KVKey) -> Result<bool, Errno> {
+            if self.live_entry(key).is_none() {
+                return Ok(false);
+            }
+
+            let record = LogRecord {
+                tombstone: true,
+                version: self.last_version + 1,
+                expires: 0,
+                key: key.as_bytes().to_vec(),
+                value: Vec::new(),
+            };
+            let (segment, offset) = self.append(&record.to_bytes())?;
+            self.last_version = record.version;
+            let hint = HintEntry {
+                key: *key,
+                tombstone: true,
+                offset: offset + record.value_offset(),
+                len: 0,
+                version: record.version,
+                expires: 0,
+            };
+            self.apply_hints(segment, &mut [hint]);
+            self.active_hints.push(hint);
+            Ok(true)
+        }
+
+        /// Apply ops as one write if the version of every guarded key matches, 0 standing
+        /// for a key that is not present. Returns the version given to the writes, or None
+        /// without writing anything if a guard failed.
+        pub fn commit(&mut self, guards: &[(KVKey, u64)], ops: &[TxnOp]) -> Result<Option<u64>, Errno> {
+            for (key, version) in guards {
+                if self.version(key) != *version {
+                    return Ok(None);
+                }
+            }
+
+            let batch = LogBatch {
+                version: self.last_version + 1,
+                ops,
+            };
+            let (segment, offset) = self.append(&batch.to_bytes())?;
+            self.last_version = batch.version;
+
+            /* walk the ops the way to_bytes laid them out to find each value */
+            let mut pos = offset + (RECORD_HEADER_LEN + RECORD_VERSION_LEN) as u64;
+            let mut hints: Vec<HintEntry> = Vec::with_capacity(ops.len());
+            for op in ops {
+                let (key, tombstone, len, expires) = match op {
+                    TxnOp::Set(key, value, expires) => (**key, false, value.len() as u64, *expires),
+                    TxnOp::Delete(key) => (**key, true, 0, 0),
+                };
+                pos += (BATCH_OP_HEADER_LEN + expires_len(expires) + key.as_bytes().len()) as u64;
+                hints.push(HintEntry { key, tombstone, offset: pos, len, version: batch.version, expires });
+                pos += len;
+            }
+            self.apply_hints(segment, &mut hints);
+            self.active_hints.extend(hints);
+            Ok(Some(batch.version))
+        }
+
+        fn index_insert(&mut self, key: KVKey, entry: IndexEntry) {
+            self.live_bytes += entry.record_len(&key);
+            if entry.expires != 0 {
+                self.expiring.insert((entry.expires, key));
+            }
+            if let Some(old) = self.index.insert(key, entry) {
+                self.live_bytes -= old.record_len(&key);
+                if old.expires != 0 && old.expires != entry.expires {
+                    self.expiring.remove(&(old.expires, key));
+                }
+            }
+        }
+
+        fn index_remove(&mut self, key: &KVKey) {
+            if let Some(old) = self.index.remove(key) {
+                self.live_bytes -= old.record_len(key);
+                if old.expires != 0 {
+                    self.expiring.remove(&(old.expires, *key));
+                }
+            }
+        }
+
+        /// Drop every expired key from the index, returns how many were dropped.
+        /// Takes the store lock itself, a batch of keys at a time. Nothing is written to
+        /// the log: replay skips expired records on its own, and compaction reclaims them.
+        pub fn expire(&mut self) -> Result<usize, Errno> {
+            let mut expired: usize = 0;
+            loop {
+                self.lock()?;
+                let now = now_millis();
+                let mut batch: usize = 0;
+                while batch < EXPIRE_BATCH {
+                    match self.expiring.first() {
+                        Some((expires, key)) if *expires <= now => {
+                            let key = *key;
+                            self.index_remove(&key);
+                            batch += 1;
+                        },
+                        _ => break,
+                    }
+                }
+                self.unlock()?;
+                expired += batch;
+                if batch < EXPIRE_BATCH {
+                    return Ok(expired);
+                }
+            }
+        }
+
+        /// Sizes of the store, for the admin console. Takes the store lock itself
+        pub fn stats(&mut self) -> Result<StoreStats, Errno> {
+            self.lock()?;
+            let stats = StoreStats {
+                keys: self.index.len(),
+                expiring: self.expiring.len(),
+                segments: self.segments.len(),
+                total_bytes: self.total_bytes(),
+                live_bytes: self.live_bytes,
+                last_version: self.last_version,
+                compacting: self.compacting,
+            };
+            self.unlock()?;
+            Ok(stats)
+        }
+
+        /// Copy of the log as it is now in dir, which a server can be started on.
+        /// The active segment is sealed first, then every sealed segment and its hint
+        /// file is hard linked into dir, copied if dir is on another filesystem.
+        /// Sealed files are never written again so the links stay a consistent copy,
+        /// and an empty segment after them is what a server on dir appends to.
+        /// Takes the store lock itself, only to seal and list the segments: they are linked
+        /// with the compacting flag set instead, so no compaction removes them meanwhile.
+        /// Fails with EBUSY while a compaction is running. Returns the number of segments
+        pub fn snapshot(&mut self, dir: &Path) -> Result<usize, Errno> {
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                return Err(Errno::from_raw(e.raw_os_error().unwrap_or(0)));
+            }
+            self.lock()?;
+            if self.compacting {
+                self.unlock()?;
+                return Err(Errno::EBUSY);
+            }
+            let sealed = self.seal_all();
+            self.compacting = sealed.is_ok();
+            self.unlock()?;
+            let ids = sealed?;
+
+            let result = self.link_sealed(dir, &ids);
+            self.lock()?;
+            self.compacting = false;
+            self.unlock()?;
+            result.map(|_| ids.len())
+        }
+
+        /// Seal the active segment unless it is empty, returns the ids of every sealed segment
+        fn seal_all(&mut self) -> Result<Vec<u32>, Errno> {
+            let active = &self.segments[&self.active];
+            if active.len > 0 {
+                fsync(&active.fd)?;
+                self.roll(self.active + 1)?;
+            }
+            Ok(self.segments.keys().copied().filter(|id| *id != self.active).collect())
+        }
+
+        fn link_sealed(&self, dir: &Path, ids: &[u32]) -> Result<(), Errno> {
+            for id in ids {
+                let src = segment_path(&self.config.dir, *id);
+                link_or_copy(&src, &segment_path(dir, *id))?;
+                let hint = hint_path(&self.config.dir, *id);
+                if hint.exists() {
+                    link_or_copy(&hint, &hint_path(dir, *id))?;
+                }
+            }
+
+            /* recovery appends to the highest segment, which must not be a link into this store */
+            let active = ids.last().map_or(1, |id| id + 1);
+            let fd = open(&segment_path(dir, active), OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_CLOEXEC, Mode::S_IRUSR | Mode::S_IWUSR)?;
+            fsync(&fd)
+        }
+
+        fn total_bytes(&self) -> u64 {
+            self.segments.values().map(|s| s.len).sum()
+        }
+
+        /// True once enough of the log is stale records to be worth rewriting
+        fn needs_compaction(&self) -> bool {
+            let total = self.total_bytes();
+            total >= COMPACT_MIN_BYTES
+                && (total - self.live_bytes) as f64 >= total as f64 * COMPACT_STALE_RATIO
+        }
+
+        /// Merge every sealed segment into as few new segments as their live records need,
+        /// returns false if there was nothing worth compacting, or another compaction is
+        /// running. force compacts however little of the log is stale. Takes the store lock
+        /// itself: it is held to start a new active segment and copy out the index entries,
+        /// and again to swap the index over to the new segments. Flushing the old active
+        /// segment, sorting and copying records all happen without it.
+        pub fn compact(&mut self, force: bool) -> Result<bool, Errno> {
+            self.lock()?;
+            let start = !self.compacting && (force || self.needs_compaction());
+            self.compacting |= start;
+            self.unlock()?;
+            if !start {
+                return Ok(false);
+            }
+
+            let result = self.merge();
+            self.lock()?;
+            self.compacting = false;
+            self.unlock()?;
+            result
+        }
+
+        fn merge(&mut self) -> Result<bool, Errno> {
+
+            /* seal the active segment, new appends go to a segment numbered above the
+             * ids the merged output will take, so replay order stays oldest first */
+            self.lock()?;
+            let sealed: Vec<u32> = self.segments.keys().copied().collect();
+            let first_out = self.active + 1;
+            let next_active = self.active + sealed.len() as u32 + 1;
+            let rolled = match self.begin_roll(next_active) {
+                Ok(rolled) => rolled,
+                Err(e) => {
+                    self.unlock()?;
+                    return Err(e);
+                }
+            };
+
+            /* expired values are not copied, and leave the index with their segment */
+            let now = now_millis();
+            let last_version = self.last_version;
+            let mut snapshot: Vec<(KVKey, IndexEntry)> = Vec::new();
+            let mut dropped: Vec<(KVKey, IndexEntry)> = Vec::new();
+            for (key, entry) in self.index.iter().filter(|(_, e)| e.segment < next_active) {
+                match entry.is_expired(now) {
+                    false => snapshot.push((*key, *entry)),
+                    true => dropped.push((*key, *entry)),
+                }
+            }
+
+            let srcs: Result<HashMap<u32, OwnedFd>, Errno> = sealed.iter()
+                .map(|id| dup(&self.segments[id].fd).map(|fd| (*id, fd)))
+                .collect();
+            self.unlock()?;
+
+            /* the rest runs without the store lock, until the swap */
+            if let Some(rolled) = rolled {
+                self.finish_roll(rolled)?;
+            }
+            let srcs = srcs?;
+            snapshot.sort_by_key(|(_, e)| (e.segment, e.offset));
+
+            /* a failed or abandoned merge leaves no output behind, renamed or not */
+            let mut outputs: Vec<(u32, Segment)> = Vec::new();
+            let mut moved: Vec<(KVKey, IndexEntry, IndexEntry)> = Vec::with_capacity(snapshot.len());
+            match self.write_outputs(snapshot, last_version, &srcs, first_out..next_active, &mut outputs, &mut moved) {
+                Ok(true) => (),
+                Ok(false) => {
+                    self.discard_compaction(&outputs);
+                    return Ok(false);
+                }
+                Err(e) => {
+                    self.discard_compaction(&outputs);
+                    return Err(e);
+                }
+            }
+
+            /* swap: keys rewritten since the snapshot keep their newer entry */
+            self.lock()?;
+            let old_bytes: u64 = sealed.iter().map(|id| self.segments[id].len).sum();
+            let new_bytes: u64 = outputs.iter().map(|(_, out)| out.len).sum();
+            for (key, old, new) in moved {
+                if let Some(current) = self.index.get_mut(&key)
+                    && *current == old {
+                    *current = new;
+                }
+            }
+            for (key, old) in dropped {
+                if self.index.get(&key) == Some(&old) {
+                    self.index_remove(&key);
+                }
+            }
+            for id in &sealed {
+                self.segments.remove(id);
+            }
+            for (id, out) in outputs {
+                self.segments.insert(id, out);
+            }
+            self.unlock()?;
+
+            for id in &sealed {
+                if let Err(e) = unlink(&segment_path(&self.config.dir, *id)) {
+                    eprintln!("storage::compact: unlink segment {}: {}", id, e);
+                }
+                match unlink(&hint_path(&self.config.dir, *id)) {
+                    Ok(_) | Err(Errno::ENOENT) => (),
+                    Err(e) => eprintln!("storage::compact: unlink hint {}: {}", id, e),
+                }
+            }
+
+            kv_info!("storage::compact: {} segments, {} -> {} bytes", sealed.len(), old_bytes, new_bytes);
+            Ok(true)
+        }
+
+        /// Copy the live records of snapshot into new segments numbered from ids, written
+        /// under a temporary name and renamed once synced. Returns false if they need more
+        /// ids than were reserved. Whatever was created is left in outputs for the caller
+        fn write_outputs(&self, snapshot: Vec<(KVKey, IndexEntry)>, last_version: u64, srcs: &HashMap<u32, OwnedFd>, ids: Range<u32>, outputs: &mut Vec<(u32, Segment)>, moved: &mut Vec<(KVKey, IndexEntry, IndexEntry)>) -> Result<bool, Errno> {
+            /* the first record keeps the highest version given out so far, as a delete of
+             * the empty key ahead of its live record if it has one. The records that held
+             * it may be dropped here, and recovery must never give out a version twice */
+            let mark = (KVKey::from_slice(&[]).unwrap(), None);
+            let records = std::iter::once(mark).chain(snapshot.into_iter().map(|(key, entry)| (key, Some(entry))));
+
+            /* copy live records into fresh segments, written under a temporary name */
+            let mut output_hints: Vec<Vec<HintEntry>> = Vec::new();
+            for (key, entry) in records {
+                let record = match entry {
+                    Some(entry) => {
+                        let mut value = vec![0u8; entry.len as usize];
+                        pread_exact(&srcs[&entry.segment], &mut value, entry.offset)?;
+                        LogRecord {
+                            tombstone: false,
+                            version: entry.version,
+                            expires: entry.expires,
+                            key: key.as_bytes().to_vec(),
+                            value,
+                        }
+                    },
+                    None => LogRecord {
+                        tombstone: true,
+                        version: last_version,
+                        expires: 0,
+                        key: Vec::new(),
+                        value: Vec::new(),
+                    },
+                };
+                let bytes = record.to_bytes();
+
+                let needs_new = match outputs.last() {
+                    Some((_, out)) => out.len > 0 && out.len + bytes.len() as u64 > self.config.segment_max_bytes,
+                    None => true,
+                };
+                if needs_new {
+                    let id = ids.start + outputs.len() as u32;
+                    if id >= ids.end {
+                        /* live data no longer fits the reserved ids, try again next round */
+                        return Ok(false);
+                    }
+                    let fd = open(compact_path(&self.config.dir, id).as_os_str(), OFlag::O_RDWR | OFlag::O_CREAT | OFlag::O_TRUNC | OFlag::O_CLOEXEC, Mode::S_IRUSR | Mode::S_IWUSR)?;
+                    outputs.push((id, Segment { fd, len: 0 }));
+                    output_hints.push(Vec::new());
+                }
+
+                let (id, out) = outputs.last_mut().unwrap();
+                pwrite_all(&out.fd, &bytes, out.len)?;
+                let new_entry = IndexEntry {
+                    segment: *id,
+                    offset: out.len + record.value_offset(),
+                    len: record.value.len() as u64,
+                    version: record.version,
+                    expires: record.expires,
+                };
+                output_hints.last_mut().unwrap().push(HintEntry { key, tombstone: record.tombstone, offset: new_entry.offset, len: new_entry.len, version: new_entry.version, expires: new_entry.expires });
+                if let Some(entry) = entry {
+                    moved.push((key, entry, new_entry));
+                }
+                out.len += bytes.len() as u64;
+            }
+
+            for ((id, out), hints) in outputs.iter().zip(&output_hints) {
+                fsync(&out.fd)?;
+                renameat(AT_FDCWD, compact_path(&self.config.dir, *id).as_os_str(), AT_FDCWD, &segment_path(&self.config.dir, *id))?;
+                if let Err(e) = write_hint(&self.config.dir, *id, out.len, hints) {
+                    eprintln!("storage::compact: write hint for segment {}: {}", id, e);
+                }
+            }
+            Ok(true)
+        }
+
+        fn discard_compaction(&self, outputs: &[(u32, Segment)]) {
+            for (id, _) in outputs {
+                for path in [PathBuf::from(compact_path(&self.config.dir, *id)), segment_path(&self.config.dir, *id), hint_path(&self.config.dir, *id)] {
+                    match unlink(&path) {
+                        Ok(_) | Err(Errno::ENOENT) => (),
+                        Err(e) => eprintln!("storage::compact: unlink {}: {}", path.display(), e),
+                    }
+                }
+            }
+        }
+
+        /// Replay every segment, oldest first, into the index. Sealed segments are loaded
+        /// from their hint file when it is present and intact, otherwise scanned and given
+        /// a fresh hint file. A torn or corrupt tail is truncated so later appends start on
+        /// a record boundary.
+        fn recover(&mut self) -> Result<(), Errno> {
+            let mut ids: Vec<u32> = Vec::new();
+            let entries = match std::fs::read_dir(&self.config.dir) {
+                Ok(entries) => entries,
+                Err(e) => return Err(Errno::from_raw(e.raw_os_error().unwrap_or(0))),
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if name.ends_with(".compact") {
+                    /* leftover from a compaction that never finished */
+                    let _ = unlink(&entry.path());
+                    continue;
+                }
+                if let Some(id) = parse_segment_name(&name) {
+                    ids.push(id);
+                }
+            }
+            ids.sort();
+
+            let last = ids.last().copied();
+            let mut hinted: usize = 0;
+            for id in ids {
+                let fd = open(&segment_path(&self.config.dir, id), OFlag::O_RDWR | OFlag::O_CLOEXEC, Mode::empty())?;
+                let file_len = fstat(&fd)?.st_size as u64;
+
+                if Some(id) == last {
+                    /* the active segment is appended to again, its hints are rebuilt on seal */
+                    let (len, hints) = self.replay_segment(id, &fd)?;
+                    match unlink(&hint_path(&self.config.dir, id)) {
+                        Ok(_) | Err(Errno::ENOENT) => (),
+                        Err(e) => return Err(e),
+                    }
+                    self.active_hints = hints;
+                    self.segments.insert(id, Segment { fd, len });
+                } else if let Some(mut hints) = read_hint(&self.config.dir, id, file_len)? {
+                    self.apply_hints(id, &mut hints);
+                    self.segments.insert(id, Segment { fd, len: file_len });
+                    hinted += 1;
+                } else {
+                    let (len, hints) = self.replay_segment(id, &fd)?;
+                    if let Err(e) = write_hint(&self.config.dir, id, len, &hints) {
+                        eprintln!("storage::recover: write hint for segment {}: {}", id, e);
+                    }
+                    self.segments.insert(id, Segment { fd, len });
+                }
+                self.active = id;
+            }
+            if hinted > 0 {
+                kv_info!("storage::recover: loaded {} of {} segments from hint files", hinted, self.segments.len());
+            }
+
+            if self.segments.is_empty() {
+                self.roll(1)?;
+            }
+            Ok(())
+        }
+
+        /// Apply hint entries of segment id to the index, in record order.
+        /// Entries of records written before versions are given the next version up.
+        /// A value that has already expired removes the key like a delete would.
+        fn apply_hints(&mut self, id: u32, hints: &mut [HintEntry]) {
+            let now = now_millis();
+            for hint in hints {
+                if hint.version == 0 {
+                    hint.version = self.last_version + 1;
+                }
+                self.last_version = std::cmp::max(self.last_version, hint.version);
+
+                let entry = IndexEntry { segment: id, offset: hint.offset, len: hint.len, version: hint.version, expires: hint.expires };
+                if hint.tombstone || entry.is_expired(now) {
+                    self.index_remove(&hint.key);
+                } else {
+                    self.index_insert(hint.key, entry);
+                }
+            }
+        }
+
+        /// Apply every record of a segment to the index,
+        /// returns the segment's valid length and the hints for its records
+        fn replay_segment(&mut self, id: u32, fd: &OwnedFd) -> Result<(u64, Vec<HintEntry>), Errno> {
+            let file_len = fstat(fd)?.st_size as u64;
+            let mut offset: u64 = 0;
+            let mut hints: Vec<HintEntry> = Vec::new();
+
+            while offset < file_len {
+                let (mut record_hints, next) = match read_record(fd, offset, file_len)? {
+                    Some(record) => record,
+                    None => {
+                        eprintln!("storage::recover: bad record in segment {} at offset {}, truncating {} bytes", id, offset, file_len - offset);
+                        ftruncate(fd, offset as i64)?;
+                        break;
+                    }
+                };
+
+                self.apply_hints(id, &mut record_hints);
+                hints.extend(record_hints);
+                offset = next;
+            }
+
+            Ok((offset, hints))
+        }
+
+        /// Start the new, empty segment id as the active one for a caller that seals the old
+        /// one without the store lock, see finish_roll. Must hold the store lock.
+        /// The old segment counts as being flushed until then, so that no writer takes
+        /// a flush of the new active segment to cover writes still in the old one
+        fn begin_roll(&mut self, id: u32) -> Result<Option<Rolled>, Errno> {
+            while self.syncing {
+                kv_cond_wait(&mut self.synced, &mut self.mtx)?;
+            }
+            let old = match self.segments.get(&self.active) {
+                Some(old) => Some((dup(&old.fd)?, old.len)),
+                None => None,
+            };
+            let fd = open(&segment_path(&self.config.dir, id), OFlag::O_RDWR | OFlag::O_CREAT | OFlag::O_TRUNC | OFlag::O_CLOEXEC, Mode::S_IRUSR | Mode::S_IWUSR)?;
+
+            let rolled = old.map(|(fd, len)| Rolled {
+                id: self.active,
+                fd,
+                len,
+                hints: std::mem::take(&mut self.active_hints),
+                seq: self.write_seq,
+            });
+            self.syncing = rolled.is_some() && self.config.durability != Durability::Never;
+            self.segments.insert(id, Segment { fd, len: 0 });
+            self.active = id;
+            Ok(rolled)
+        }
+
+        /// Flush and write the hint file of the segment begin_roll sealed.
+        /// Takes the store lock itself, and does not hold it during the fsync.
+        fn finish_roll(&mut self, rolled: Rolled) -> Result<(), Errno> {
+            if self.config.durability != Durability::Never {
+                let result = fsync(&rolled.fd);
+                self.lock()?;
+                self.syncing = false;
+                let result = match result {
+                    Ok(_) => self.mark_synced(rolled.seq),
+                    Err(e) => kv_cond_broadcast(&mut self.synced).and(Err(e)),
+                };
+                self.unlock()?;
+                result?;
+            }
+            if let Err(e) = write_hint(&self.config.dir, rolled.id, rolled.len, &rolled.hints) {
+                eprintln!("storage::roll: write hint for segment {}: {}", rolled.id, e);
+            }
+            Ok(())
+        }
+
+        /// Seal the active segment, if any, and start a new, empty one.
+        /// A failed hint write is only logged, recovery falls back to scanning the segment.
+        fn roll(&mut self, id: u32) -> Result<(), Errno> {
+            if let Some(sealed) = self.segments.get(&self.active) {
+                /* nothing is written to a sealed segment again, so flush it now and
+                 * later flushes only need to cover the new active segment */
+                if self.config.durability != Durability::Never {
+                    fsync(&sealed.fd)?;
+                    self.mark_synced(self.write_seq)?;
+                }
+                let sealed = &self.segments[&self.active];
+                if let Err(e) = write_hint(&self.config.dir, self.active, sealed.len, &self.active_hints) {
+                    eprintln!("storage::roll: write hint for segment {}: {}", self.active, e);
+                }
+                self.active_hints.clear();
+            }
+
+            let fd = open(&segment_path(&self.config.dir, id), OFlag::O_RDWR | OFlag::O_CREAT | OFlag::O_TRUNC | OFlag::O_CLOEXEC, Mode::S_IRUSR | Mode::S_IWUSR)?;
+            self.segments.insert(id, Segment { fd, len: 0 });
+            self.active = id;
+            Ok(())
+        }
+
+        /// Write an encoded record at the end of the active segment, rolling over first if
+        /// it would pass the size limit. Returns the segment id and offset it landed at.
+        fn append(&mut self, bytes: &[u8]) -> Result<(u32, u64), Errno> {
+            let active_len = self.segments[&self.active].len;
+            if active_len > 0 && active_len + bytes.len() as u64 > self.config.segment_max_bytes {
+                self.roll(self.active + 1)?;
+            }
+
+            let segment = self.segments.get_mut(&self.active).unwrap();
+            let offset = segment.len;
+            pwrite_all(&segment.fd, bytes, offset)?;
+            segment.len += bytes.len() as u64;
+            self.write_seq += 1;
+            Ok((self.active, offset))
+        }
+    }
+
+    /// Read and verify the record at offset, returns its Sets and Deletes as hint entries
+    /// and the offset of the next record. None if it is truncated or fails its checksum.
+    fn read_record(fd: &OwnedFd, offset: u64, file_len: u64) -> Result<Option<(Vec<HintEntry>, u64)>, Errno> {
+        if file_len - offset < RECORD_HEADER_LEN as u64 {
+            return Ok(None);
+        }
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        pread_exact(fd, &mut header, offset)?;
+        let (crc, flags, key_len, val_len) = LogRecord::parse_header(&header);
+        let version_len = if flags & RECORD_VERSIONED != 0 { RECORD_VERSION_LEN } else { 0 };
+        let expires_len = if flags & RECORD_EXPIRES != 0 { 8 } else { 0 };
+
+        /* lengths come from disk, check them before allocating */
+        if key_len > KVKey::MAX_LEN {
+            return Ok(None);
+        }
+        let body_len = match ((version_len + expires_len + key_len) as u64).checked_add(val_len as u64) {
+            Some(len) if len <= file_len - offset - RECORD_HEADER_LEN as u64 => len,
+            _ => return Ok(None),
+        };
+
+        let mut body = vec![0u8; body_len as usize];
+        pread_exact(fd, &mut body, offset + RECORD_HEADER_LEN as u64)?;
+
+        let mut check: Vec<u8> = Vec::with_capacity(RECORD_HEADER_LEN - 4 + body.len());
+        check.extend(&header[4..]);
+        check.extend(&body);
+        if crc32(&check) != crc {
+            return Ok(None);
+        }
+
+        let version = match version_len {
+            0 => 0,
+            _ => u64::from_le_bytes(body[0..8].try_into().unwrap()),
+        };
+        let expires = match expires_len {
+            0 => 0,
+            _ => u64::from_le_bytes(body[version_len..version_len + 8].try_into().unwrap()),
+        };
+        let next = offset + RECORD_HEADER_LEN as u64 + body_len;
+        let body_offset = offset + (RECORD_HEADER_LEN + version_len + expires_len) as u64;
+        let body = &body[version_len + expires_len..];
+
+        if flags & RECORD_BATCH == 0 {
+            let key = match KVKey::from_slice(&body[..key_len]) {
+                Ok(key) => key,
+                Err(_) => return Ok(None),
+            };
+            let hint = HintEntry {
+                key,
+                tombstone: flags & RECORD_TOMBSTONE != 0,
+                offset: body_offset + key_len as u64,
+                len: val_len as u64,
+                version,
+                expires,
+            };
+            return Ok(Some((vec![hint], next)));
+        }
+
+        /* the checksum passed, so a batch that does not parse was written wrong, not torn */
+        let mut hints: Vec<HintEntry> = Vec::new();
+        let mut pos = key_len;
+        while pos < body.len() {
+            if body.len() - pos < BATCH_OP_HEADER_LEN {
+                return Ok(None);
+            }
+            let op_flags = body[pos];
+            let op_key_len = u32::from_le_bytes(body[pos + 1..pos + 5].try_into().unwrap()) as usize;
+            let op_val_len = u64::from_le_bytes(body[pos + 5..pos + 13].try_into().unwrap());
+            pos += BATCH_OP_HEADER_LEN;
+            let op_expires = if op_flags & RECORD_EXPIRES != 0 {
+                if body.len() - pos < 8 {
+                    return Ok(None);
+                }
+                pos += 8;
+                u64::from_le_bytes(body[pos - 8..pos].try_into().unwrap())
+            } else {
+                0
+            };
+            if op_key_len > KVKey::MAX_LEN {
+                return Ok(None);
+            }
+            match (op_key_len as u64).checked_add(op_val_len) {
+                Some(len) if len <= (body.len() - pos) as u64 => {}
+                _ => return Ok(None),
+            }
+            let key = match KVKey::from_slice(&body[pos..pos + op_key_len]) {
+                Ok(key) => key,
+                Err(_) => return Ok(None),
+            };
+            pos += op_key_len;
+            hints.push(HintEntry {
+                key,
+                tombstone: op_flags & RECORD_TOMBSTONE != 0,
+                offset: body_offset + pos as u64,
+                len: op_val_len,
+                version,
+                expires: op_expires,
+            });
+            pos += op_val_len as usize;
+        }
+        Ok(Some((hints, next)))
+    }
+
+    /// Hard link src at dst, or copy it where links are not possible
+    fn link_or_copy(src: &Path, dst: &Path) -> Result<(), Errno> {
+        let result = match std::fs::hard_link(src, dst) {
+            Err(e) if e.raw_os_error() == Some(Errno::EXDEV as i32) => std::fs::copy(src, dst).map(|_| ()),
+            result => result,
+        };
+        result.map_err(|e| Errno::from_raw(e.raw_os_error().unwrap_or(0)))
+    }
+
+    /// dir/00000001.kvlog
+    pub fn segment_path(dir: &Path, id: u32) -> PathBuf {
+        dir.join(format!("{:08}.{}", id, SEGMENT_EXT))
+    }
 
-/// Delete key value pair from log
-pub fn log_del(){}
+    /// dir/00000001.kvhint
+    pub fn hint_path(dir: &Path, id: u32) -> PathBuf {
+        dir.join(format!("{:08}.{}", id, HINT_EXT))
+    }
 
-/// Open unix tcp socket, bind and listen
-pub fn open_socket(path: &Path) -> Result<OwnedFd, Errno>{
-    let socket_addr = UnixAddr::new(path).expect("open_socket: UnixAddr failed");
+    /// Write the hint file for segment id
+    fn write_hint(dir: &Path, id: u32, seg_len: u64, hints: &[HintEntry]) -> Result<(), Errno> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(HINT_HEADER_LEN + hints.len() * (HINT_ENTRY_LEN + RECORD_VERSION_LEN + 16) + 4);
+        bytes.extend(&seg_len.to_le_bytes());
+        bytes.extend(&(hints.len() as u64).to_le_bytes());
+        for hint in hints {
+            let key = hint.key.as_bytes();
+            let mut flags: u8 = RECORD_VERSIONED;
+            if hint.tombstone { flags |= RECORD_TOMBSTONE; }
+            if hint.expires != 0 { flags |= RECORD_EXPIRES; }
+            bytes.push(flags);
+            bytes.extend(&(key.len() as u32).to_le_bytes());
+            bytes.extend(&id.to_le_bytes());
+            bytes.extend(&hint.offset.to_le_bytes());
+            bytes.extend(&hint.len.to_le_bytes());
+            bytes.extend(&hint.version.to_le_bytes());
+            if hint.expires != 0 {
+                bytes.extend(&hint.expires.to_le_bytes());
+            }
+            bytes.extend(key);
+        }
+        let crc = crc32(&bytes);
+        bytes.extend(&crc.to_le_bytes());
 
-    let _ = match unlink(path){
-        Ok(_) => (),
-        Err(Errno::ENOENT) => (), /* .sock already exists, continue */
-        Err(e) => {
-            eprintln!("open_socket: unlink socket_path: {}", e);
-            return Err(e);
+        let fd = open(&hint_path(dir, id), OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC | OFlag::O_CLOEXEC, Mode::S_IRUSR | Mode::S_IWUSR)?;
+        pwrite_all(&fd, &bytes, 0)?;
+        fsync(&fd)
+    }
+
+    /// Read the hint file for segment id, None if it is missing, fails its checksum
+    /// or describes a segment of a different length than seg_len
+    fn read_hint(dir: &Path, id: u32, seg_len: u64) -> Result<Option<Vec<HintEntry>>, Errno> {
+        let fd = match open(&hint_path(dir, id), OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty()) {
+            Ok(fd) => fd,
+            Err(Errno::ENOENT) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let file_len = fstat(&fd)?.st_size as usize;
+        if file_len < HINT_HEADER_LEN + 4 {
+            return Ok(None);
         }
-    };
+        let mut bytes = vec![0u8; file_len];
+        pread_exact(&fd, &mut bytes, 0)?;
 
-    let sockfd = socket(
-        AddressFamily::Unix, 
-        SockType::Stream,
-        SockFlag::empty(),
-        None,
-    ).expect("open_socket: socket failed");
+        let (body, crc) = bytes.split_at(file_len - 4);
+        if crc32(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+            return Ok(None);
+        }
+        if u64::from_le_bytes(body[0..8].try_into().unwrap()) != seg_len {
+            return Ok(None);
+        }
 
-    bind(sockfd.as_raw_fd(), &socket_addr).expect("open_socket: bind failed");
-    listen(&sockfd, Backlog::MAXCONN).expect("open_socket: listen failed");
+        let count = u64::from_le_bytes(body[8..16].try_into().unwrap()) as usize;
+        let mut hints: Vec<HintEntry> = Vec::with_capacity(std::cmp::min(count, body.len() / HINT_ENTRY_LEN));
+        let mut pos = HINT_HEADER_LEN;
+        for _ in 0..count {
+            if body.len() - pos < HINT_ENTRY_LEN {
+                return Ok(None);
+            }
+            let e = &body[pos..pos + HINT_ENTRY_LEN];
+            let key_len = u32::from_le_bytes(e[1..5].try_into().unwrap()) as usize;
+            let segment = u32::from_le_bytes(e[5..9].try_into().unwrap());
+            let offset = u64::from_le_bytes(e[9..17].try_into().unwrap());
+            let len = u64::from_le_bytes(e[17..25].try_into().unwrap());
+            pos += HINT_ENTRY_LEN;
+            let version = if e[0] & RECORD_VERSIONED != 0 {
+                if body.len() - pos < RECORD_VERSION_LEN {
+                    return Ok(None);
+                }
+                pos += RECORD_VERSION_LEN;
+                u64::from_le_bytes(body[pos - RECORD_VERSION_LEN..pos].try_into().unwrap())
+            } else {
+                0
+            };
+            let expires = if e[0] & RECORD_EXPIRES != 0 {
+                if body.len() - pos < 8 {
+                    return Ok(None);
+                }
+                pos += 8;
+                u64::from_le_bytes(body[pos - 8..pos].try_into().unwrap())
+            } else {
+                0
+            };
+            if segment != id || body.len() - pos < key_len {
+                return Ok(None);
+            }
+            let key = match KVKey::from_slice(&body[pos..pos + key_len]) {
+                Ok(key) => key,
+                Err(_) => return Ok(None),
+            };
+            pos += key_len;
+            hints.push(HintEntry { key, tombstone: e[0] & RECORD_TOMBSTONE != 0, offset, len, version, expires });
+        }
 
-    Ok(sockfd)
-}
+        Ok(Some(hints))
+    }
 
-pub fn accept_connection(socket_fd: &OwnedFd, rbuf: &mut FdRingBuffer) -> Result<(), Errno>{
-    /* todo: write connectionfd into a buffer */
+    /// Temporary name of a segment being written by compaction
+    fn compact_path(dir: &Path, id: u32) -> OsString {
+        let mut path = segment_path(dir, id).into_os_string();
+        path.push(".compact");
+        path
+    }
 
-    let connfd_raw: RawFd = accept(socket_fd.as_raw_fd()).expect("accept failed");
-    let connfd = unsafe { OwnedFd::from_raw_fd(connfd_raw) };
-    rbuf.put(connfd).expect("FdRingBuffer full or bad put");
-    return Ok(());
+    /// Segment id from a file name like 00000001.kvlog
+    fn parse_segment_name(name: &str) -> Option<u32> {
+        let stem = name.strip_suffix(SEGMENT_EXT)?.strip_suffix('.')?;
+        if stem.is_empty() || !stem.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        stem.parse().ok()
+    }
+
+    /// Ensures full write of buf at offset
+    fn pwrite_all(fd: &OwnedFd, buf: &[u8], offset: u64) -> Result<(), Errno> {
+        let mut nbytes_written: usize = 0;
+        while nbytes_written < buf.len() {
+            match pwrite(fd, &buf[nbytes_written..], (offset + nbytes_written as u64) as i64) {
+                Ok(n) => nbytes_written += n,
+                Err(Errno::EINTR) => continue,
+                Err(e) => {
+                    eprintln!("storage::pwrite_all error: {}", e);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Ensures full read of buf from offset, EIO if the file ends first
+    fn pread_exact(fd: &OwnedFd, buf: &mut [u8], offset: u64) -> Result<(), Errno> {
+        let mut nbytes_read: usize = 0;
+        while nbytes_read < buf.len() {
+            match pread(fd, &mut buf[nbytes_read..], (offset + nbytes_read as u64) as i64) {
+                Ok(0) => return Err(Errno::EIO),
+                Ok(n) => nbytes_read += n,
+                Err(Errno::EINTR) => continue,
+                Err(e) => {
+                    eprintln!("storage::pread_exact error: {}", e);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    const CRC32_TABLE: [u32; 256] = crc32_table();
+
+    const fn crc32_table() -> [u32; 256] {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut c = i as u32;
+            let mut k = 0;
+            while k < 8 {
+                c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+                k += 1;
+            }
+            table[i] = c;
+            i += 1;
+        }
+        table
+    }
+
+    /// CRC-32 (IEEE) of bytes
+    pub fn crc32(bytes: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for b in bytes {
+            crc = CRC32_TABLE[((crc ^ *b as u32) & 0xFF) as usize] ^ (crc >> 8);
+        }
+        !crc
+    }
 }
 
 pub mod polling {
@@ -69,6 +1692,7 @@ pub mod threading {
     use nix::errno::Errno;    
     
     /// Wrapper for libc::pthread_create, takes no attributes
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
     pub fn kv_pthread_create(
         thread: *mut pthread_t,  
         thread_fn: extern "C" fn(*mut c_void) -> *mut c_void, 
@@ -103,24 +1727,27 @@ pub mod threading {
 }
 
 pub mod worker{
-    use std::{ffi::c_void, os::fd::OwnedFd};
+    use std::{ffi::c_void, os::fd::{AsRawFd, OwnedFd}, time::{Duration, SystemTime}};
 
-    use kv_shared::{io::{KVConnection, KVMsg, KVMsgType}, ringbuffer::FdRingBuffer};
-    use nix::errno::Errno;
+    use kv_shared::{io::{FEATURE_PIPELINING, GET_VERSION_SINCE, KVConnection, KVHello, KVIncr, KVKey, KVKeyList, KVMsg, KVMsgType, KVPair, KVPairList, KVPrefixScan, KVResultList, KVScan, KVScanPage, KVStatus, KVTxn, KVTxnOp, KvError, MAX_SCAN_LIMIT, MAX_VALUE_LEN, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION}, ringbuffer::FdRingBuffer};
+    use nix::{errno::Errno, sys::socket::{getsockopt, sockopt::PeerCredentials}};
     
-    use crate::threading::kv_pthread_detach;
+    use crate::{log_cas, log_commit, log_count, log_del, log_exists, log_get, log_incr, log_mdel, log_mget, log_mset, log_scan, log_set, log_strlen, registry::{ClientInfo, Registry}, storage::{IncrResult, LogStore, TxnOp, expires_after}, threading::kv_pthread_detach};
     
     /// Data passed as arg to worker_thread
     pub struct WorkerData<'a>{
         pub id: u64,
         pub rbuf: &'a mut FdRingBuffer,
+        pub store: &'a mut LogStore,
+        pub registry: &'a mut Registry,
+        pub max_frame_size: usize,
     }
     
     /// start routine for worker threads
     pub extern "C" fn worker_thread(arg: *mut c_void) -> *mut c_void{
         kv_pthread_detach().unwrap();
         let data = unsafe { Box::from_raw(arg as *mut WorkerData)};
-        println!("Hello from worker thread #{}!", data.id);
+        kv_info!("Hello from worker thread #{}!", data.id);
 
         loop {
             let fd = match data.rbuf.get(){
@@ -129,55 +1756,441 @@ pub mod worker{
                     continue;
                 }
             };
-            handle_connection(fd,data.id).expect("oops at handle_connection");
+            if let Err(e) = handle_connection(fd, data.id, data.store, data.registry, data.max_frame_size) {
+                eprintln!("worker #{}: dropped connection: {}", data.id, e);
+            }
+            if let Err(e) = data.registry.disconnected(data.id) {
+                eprintln!("worker #{}: registry error {}", data.id, e);
+            }
         }
-    
-        std::ptr::null_mut()
     }
 
-    fn handle_connection(fd: OwnedFd, workerid: u64) -> Result<(), Errno>{
-    
+    /// Features this server offers in the handshake
+    pub const SERVER_FEATURES: u64 = FEATURE_PIPELINING;
+
+    /// Expect a Hello as the first message and answer it, recording the agreed version
+    /// and features on connection. Peers that skip the handshake or share no version
+    /// with us are told so and should be dropped.
+    fn accept_hello(connection: &mut KVConnection) -> Result<(), KvError> {
+        let ours = KVHello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            features: SERVER_FEATURES,
+        };
+
+        let msg = connection.recv_kvmsg()?;
+        if msg.msgtype != KVMsgType::Hello {
+            connection.send_kvmsg(KVMsg::new_reply(KVMsgType::ErrorReturn, KVStatus::Incompatible, &ours.to_bytes()))?;
+            return Err(KvError::Protocol("first message was not Hello"));
+        }
+        let theirs = KVHello::from_bytes(&msg.msg)?;
+
+        match ours.negotiate(&theirs) {
+            Ok((version, features)) => {
+                let agreed = KVHello {
+                    min_version: version,
+                    max_version: version,
+                    features,
+                };
+                connection.send_kvmsg(KVMsg::new_reply(KVMsgType::HelloReturn, KVStatus::Ok, &agreed.to_bytes()))?;
+                connection.version = version;
+                connection.features = features;
+                Ok(())
+            },
+            Err(e) => {
+                connection.send_kvmsg(KVMsg::new_reply(KVMsgType::HelloReturn, KVStatus::Incompatible, &ours.to_bytes()))?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Status a pair is refused with before it reaches the store, Ok if it can be written
+    fn check_pair(pair: &KVPair) -> KVStatus {
+        if pair.value.len() > MAX_VALUE_LEN {
+            return KVStatus::ValueTooLarge;
+        }
+        /* flags are not supported by the storage engine yet,
+         * cas is only honoured by CompareAndSet, a ttl of 0 would expire on arrival */
+        if pair.ttl == Some(0) || pair.cas.is_some() || pair.flags != 0 {
+            return KVStatus::BadRequest;
+        }
+        KVStatus::Ok
+    }
+
+    /// Pairs a scan page may hold for the requested limit, 0 meaning as many as allowed
+    fn scan_limit(limit: u32) -> usize {
+        match limit {
+            0 => MAX_SCAN_LIMIT as usize,
+            limit => limit.min(MAX_SCAN_LIMIT) as usize,
+        }
+    }
+
+    /// Key and value bytes a scan page may hold and still fit in a frame, leaving room
+    /// for the cursor and the 10 bytes of lengths each pair is encoded with
+    fn scan_bytes(max_frame_size: usize, limit: usize) -> usize {
+        max_frame_size.saturating_sub(2 * KVKey::MAX_LEN + 64 + 10 * limit)
+    }
+
+    fn handle_connection(fd: OwnedFd, workerid: u64, store: &mut LogStore, registry: &mut Registry, max_frame_size: usize) -> Result<(), KvError>{
+
+        /* unix sockets have no address to show, the peer's process says more */
+        let (pid, uid) = match getsockopt(&fd, PeerCredentials) {
+            Ok(creds) => (creds.pid(), creds.uid()),
+            Err(_) => (0, 0),
+        };
+        let rawfd = fd.as_raw_fd();
+
         let mut connection = KVConnection{
-            fd: fd,
-            mtu: 1024,
+            fd,
+            max_frame_size,
+            version: 0,
+            features: 0,
+            next_reqid: 0,
+            last_sendtime: Duration::ZERO,
         };
+
+        if let Err(e) = accept_hello(&mut connection) {
+            match e {
+                KvError::Io(Errno::ECONNRESET) => kv_info!("worker #{}: client disconnected", workerid),
+                _ => eprintln!("worker #{}: handshake failed: {}", workerid, e),
+            }
+            return Ok(());
+        }
+        kv_info!("worker #{}: client speaks protocol version {}", workerid, connection.version);
+        registry.connected(workerid, ClientInfo {
+            fd: rawfd,
+            pid,
+            uid,
+            version: connection.version,
+            since: SystemTime::now(),
+            requests: 0,
+        })?;
     
         #[allow(unused)]
         'receive_commands: loop {
-            let msg = match connection.recv_kvmsg(){
-                Ok(msg) => msg,
-                Err(Errno::ECONNRESET) => {
-                    println!("worker #{}: client disconnected", workerid);
+            let frame = match connection.recv_frame(){
+                Ok(frame) => frame,
+                Err(KvError::Io(Errno::ECONNRESET)) => {
+                    kv_info!("worker #{}: client disconnected", workerid);
                     break;
                 },
-                Err(e) => {
+                Err(KvError::Io(e)) => {
                     eprintln!("handle_connection recv_all: error {}", e);
-                    return Err(e);
+                    return Err(KvError::Io(e));
+                },
+                Err(KvError::FrameTooLarge(len)) => {
+                    /* the body was never read, so there is no next frame to find */
+                    connection.send_kvmsg(KVMsg::new_reply(KVMsgType::ErrorReturn, KVStatus::FrameTooLarge, &[]))?;
+                    return Err(KvError::FrameTooLarge(len));
+                },
+                Err(e) => return Err(e),
+            };
+            let msg = match KVMsg::from_bytes(&frame, connection.version) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    /* the bad frame was consumed whole, tell the client which and keep going */
+                    eprintln!("worker #{}: bad request: {}", workerid, e);
+                    let mut reply = KVMsg::new_reply(KVMsgType::ErrorReturn, KVStatus::BadRequest, &[]);
+                    reply.reqid = KVMsg::reqid_of(&frame, connection.version);
+                    connection.send_kvmsg(reply)?;
+                    continue;
                 }
             };
+            registry.request(workerid)?;
         
-            match msg.msgtype {
+            let mut reply = match msg.msgtype {
                 KVMsgType::Get => {
-                    let body: Vec<u8> = String::from("good get!").into_bytes();
-                    let msg = KVMsg::new(KVMsgType::GetReturn, body);
-                    connection.send_kvmsg(msg).unwrap();
-                    println!("worker #{}: handled GET", workerid);
+                    let reply = match KVKey::from_bytes(&msg.msg) {
+                        Err(KvError::KeyTooLong(_)) => KVMsg::new_reply(KVMsgType::GetReturn, KVStatus::KeyTooLong, &[]),
+                        Err(_) => KVMsg::new_reply(KVMsgType::GetReturn, KVStatus::BadRequest, &[]),
+                        Ok(key) => match log_get(store, &key) {
+                            Ok(Some((value, version))) if connection.version >= GET_VERSION_SINCE => {
+                                let mut payload: Vec<u8> = Vec::with_capacity(8 + value.len());
+                                payload.extend(&version.to_le_bytes());
+                                payload.extend(&value);
+                                KVMsg::new_reply(KVMsgType::GetReturn, KVStatus::Ok, &payload)
+                            },
+                            Ok(Some((value, _))) => KVMsg::new_reply(KVMsgType::GetReturn, KVStatus::Ok, &value),
+                            Ok(None) => KVMsg::new_reply(KVMsgType::GetReturn, KVStatus::NotFound, &[]),
+                            Err(e) => {
+                                eprintln!("worker #{}: log_get error {}", workerid, e);
+                                KVMsg::new_reply(KVMsgType::GetReturn, KVStatus::ServerError, &[])
+                            }
+                        },
+                    };
+                    kv_debug!("worker #{}: handled GET", workerid);
+                    reply
                 },
                 KVMsgType::Set => {
-                    let body: Vec<u8> = String::from("good set!").into_bytes();
-                    let msg = KVMsg::new(KVMsgType::SetReturn, body);
-                    connection.send_kvmsg(msg).unwrap();
-                    println!("worker #{}: handled SET", workerid);
+                    let status = match KVPair::from_bytes(&msg.msg) {
+                        Err(KvError::KeyTooLong(_)) => KVStatus::KeyTooLong,
+                        Err(_) => KVStatus::BadRequest,
+                        Ok(pair) => match check_pair(&pair) {
+                            KVStatus::Ok => match log_set(store, &pair.key, &pair.value, expires_after(pair.ttl)) {
+                                Ok(()) => KVStatus::Ok,
+                                Err(e) => {
+                                    eprintln!("worker #{}: log_set error {}", workerid, e);
+                                    KVStatus::ServerError
+                                }
+                            },
+                            refused => refused,
+                        },
+                    };
+                    kv_debug!("worker #{}: handled SET", workerid);
+                    KVMsg::new_reply(KVMsgType::SetReturn, status, &[])
+                },
+                KVMsgType::CompareAndSet => {
+                    let reply = match KVPair::from_bytes(&msg.msg) {
+                        Err(KvError::KeyTooLong(_)) => KVMsg::new_reply(KVMsgType::CompareAndSetReturn, KVStatus::KeyTooLong, &[]),
+                        Err(_) => KVMsg::new_reply(KVMsgType::CompareAndSetReturn, KVStatus::BadRequest, &[]),
+                        Ok(mut pair) => match (pair.cas.take(), check_pair(&pair)) {
+                            (None, _) => KVMsg::new_reply(KVMsgType::CompareAndSetReturn, KVStatus::BadRequest, &[]),
+                            (Some(version), KVStatus::Ok) => match log_cas(store, &pair.key, &pair.value, expires_after(pair.ttl), version) {
+                                Ok(Some(version)) => KVMsg::new_reply(KVMsgType::CompareAndSetReturn, KVStatus::Ok, &version.to_le_bytes()),
+                                Ok(None) => KVMsg::new_reply(KVMsgType::CompareAndSetReturn, KVStatus::Conflict, &[]),
+                                Err(e) => {
+                                    eprintln!("worker #{}: log_cas error {}", workerid, e);
+                                    KVMsg::new_reply(KVMsgType::CompareAndSetReturn, KVStatus::ServerError, &[])
+                                }
+                            },
+                            (Some(_), refused) => KVMsg::new_reply(KVMsgType::CompareAndSetReturn, refused, &[]),
+                        },
+                    };
+                    kv_debug!("worker #{}: handled CAS", workerid);
+                    reply
+                },
+                KVMsgType::Incr | KVMsgType::Decr => {
+                    let (return_type, name) = match msg.msgtype {
+                        KVMsgType::Incr => (KVMsgType::IncrReturn, "INCR"),
+                        _ => (KVMsgType::DecrReturn, "DECR"),
+                    };
+                    let reply = match KVIncr::from_bytes(&msg.msg) {
+                        Err(KvError::KeyTooLong(_)) => KVMsg::new_reply(return_type, KVStatus::KeyTooLong, &[]),
+                        Err(_) => KVMsg::new_reply(return_type, KVStatus::BadRequest, &[]),
+                        Ok(incr) => {
+                            let delta = match msg.msgtype {
+                                KVMsgType::Incr => Some(incr.delta),
+                                _ => incr.delta.checked_neg(),
+                            };
+                            match delta.map(|delta| log_incr(store, &incr.key, delta)) {
+                                None => KVMsg::new_reply(return_type, KVStatus::Overflow, &[]),
+                                Some(Ok(IncrResult::Value(value))) => KVMsg::new_reply(return_type, KVStatus::Ok, &value.to_le_bytes()),
+                                Some(Ok(IncrResult::NotInteger)) => KVMsg::new_reply(return_type, KVStatus::NotInteger, &[]),
+                                Some(Ok(IncrResult::Overflow)) => KVMsg::new_reply(return_type, KVStatus::Overflow, &[]),
+                                Some(Err(e)) => {
+                                    eprintln!("worker #{}: log_incr error {}", workerid, e);
+                                    KVMsg::new_reply(return_type, KVStatus::ServerError, &[])
+                                }
+                            }
+                        },
+                    };
+                    kv_debug!("worker #{}: handled {}", workerid, name);
+                    reply
                 },
                 KVMsgType::Delete => {
-                    let body: Vec<u8> = String::from("good del!").into_bytes();
-                    let msg = KVMsg::new(KVMsgType::DeleteReturn, body);
-                    connection.send_kvmsg(msg).unwrap();
-                    println!("worker #{}: handled DEL", workerid);
+                    let status = match KVKey::from_bytes(&msg.msg) {
+                        Err(KvError::KeyTooLong(_)) => KVStatus::KeyTooLong,
+                        Err(_) => KVStatus::BadRequest,
+                        Ok(key) => match log_del(store, &key) {
+                            Ok(true) => KVStatus::Ok,
+                            Ok(false) => KVStatus::NotFound,
+                            Err(e) => {
+                                eprintln!("worker #{}: log_del error {}", workerid, e);
+                                KVStatus::ServerError
+                            }
+                        },
+                    };
+                    kv_debug!("worker #{}: handled DEL", workerid);
+                    KVMsg::new_reply(KVMsgType::DeleteReturn, status, &[])
+                },
+                KVMsgType::MultiGet => {
+                    let reply = match KVKeyList::from_bytes(&msg.msg) {
+                        Err(KvError::KeyTooLong(_)) => KVMsg::new_reply(KVMsgType::MultiGetReturn, KVStatus::KeyTooLong, &[]),
+                        Err(_) => KVMsg::new_reply(KVMsgType::MultiGetReturn, KVStatus::BadRequest, &[]),
+                        Ok(list) => match log_mget(store, &list.keys) {
+                            Ok(values) => {
+                                let results = values.into_iter().map(|value| match value {
+                                    Some(value) => (KVStatus::Ok, value),
+                                    None => (KVStatus::NotFound, Vec::new()),
+                                }).collect();
+                                KVMsg::new_reply(KVMsgType::MultiGetReturn, KVStatus::Ok, &KVResultList { results }.to_bytes())
+                            },
+                            Err(e) => {
+                                eprintln!("worker #{}: log_mget error {}", workerid, e);
+                                KVMsg::new_reply(KVMsgType::MultiGetReturn, KVStatus::ServerError, &[])
+                            }
+                        },
+                    };
+                    kv_debug!("worker #{}: handled MGET", workerid);
+                    reply
+                },
+                KVMsgType::MultiSet => {
+                    let reply = match KVPairList::from_bytes(&msg.msg) {
+                        Err(KvError::KeyTooLong(_)) => KVMsg::new_reply(KVMsgType::MultiSetReturn, KVStatus::KeyTooLong, &[]),
+                        Err(_) => KVMsg::new_reply(KVMsgType::MultiSetReturn, KVStatus::BadRequest, &[]),
+                        Ok(list) => {
+                            /* pairs that fail their checks are skipped, the rest are written together */
+                            let mut statuses: Vec<KVStatus> = list.pairs.iter().map(check_pair).collect();
+                            let writes: Vec<(&KVKey, &[u8], u64)> = list.pairs.iter()
+                                .zip(&statuses)
+                                .filter(|(_, status)| **status == KVStatus::Ok)
+                                .map(|(pair, _)| (&pair.key, pair.value.as_slice(), expires_after(pair.ttl)))
+                                .collect();
+                            if let Err(e) = log_mset(store, &writes) {
+                                eprintln!("worker #{}: log_mset error {}", workerid, e);
+                                for status in statuses.iter_mut().filter(|status| **status == KVStatus::Ok) {
+                                    *status = KVStatus::ServerError;
+                                }
+                            }
+                            let results = statuses.into_iter().map(|status| (status, Vec::new())).collect();
+                            KVMsg::new_reply(KVMsgType::MultiSetReturn, KVStatus::Ok, &KVResultList { results }.to_bytes())
+                        },
+                    };
+                    kv_debug!("worker #{}: handled MSET", workerid);
+                    reply
+                },
+                KVMsgType::MultiDelete => {
+                    let reply = match KVKeyList::from_bytes(&msg.msg) {
+                        Err(KvError::KeyTooLong(_)) => KVMsg::new_reply(KVMsgType::MultiDeleteReturn, KVStatus::KeyTooLong, &[]),
+                        Err(_) => KVMsg::new_reply(KVMsgType::MultiDeleteReturn, KVStatus::BadRequest, &[]),
+                        Ok(list) => match log_mdel(store, &list.keys) {
+                            Ok(deleted) => {
+                                let results = deleted.into_iter().map(|deleted| match deleted {
+                                    true => (KVStatus::Ok, Vec::new()),
+                                    false => (KVStatus::NotFound, Vec::new()),
+                                }).collect();
+                                KVMsg::new_reply(KVMsgType::MultiDeleteReturn, KVStatus::Ok, &KVResultList { results }.to_bytes())
+                            },
+                            Err(e) => {
+                                eprintln!("worker #{}: log_mdel error {}", workerid, e);
+                                KVMsg::new_reply(KVMsgType::MultiDeleteReturn, KVStatus::ServerError, &[])
+                            }
+                        },
+                    };
+                    kv_debug!("worker #{}: handled MDEL", workerid);
+                    reply
+                },
+                KVMsgType::Transaction => {
+                    let reply = match KVTxn::from_bytes(&msg.msg) {
+                        Err(KvError::KeyTooLong(_)) => KVMsg::new_reply(KVMsgType::TransactionReturn, KVStatus::KeyTooLong, &[]),
+                        Err(_) => KVMsg::new_reply(KVMsgType::TransactionReturn, KVStatus::BadRequest, &[]),
+                        Ok(txn) => {
+                            /* one refused write refuses the whole transaction */
+                            let refused = txn.ops.iter().find_map(|op| match op {
+                                KVTxnOp::Set(pair) => Some(check_pair(pair)).filter(|status| *status != KVStatus::Ok),
+                                KVTxnOp::Delete(_) => None,
+                            });
+                            let ops: Vec<TxnOp> = txn.ops.iter().map(|op| match op {
+                                KVTxnOp::Set(pair) => TxnOp::Set(&pair.key, &pair.value, expires_after(pair.ttl)),
+                                KVTxnOp::Delete(key) => TxnOp::Delete(key),
+                            }).collect();
+                            match refused {
+                                Some(status) => KVMsg::new_reply(KVMsgType::TransactionReturn, status, &[]),
+                                None => match log_commit(store, &txn.guards, &ops) {
+                                    Ok(Some(version)) => KVMsg::new_reply(KVMsgType::TransactionReturn, KVStatus::Ok, &version.to_le_bytes()),
+                                    Ok(None) => KVMsg::new_reply(KVMsgType::TransactionReturn, KVStatus::Conflict, &[]),
+                                    Err(e) => {
+                                        eprintln!("worker #{}: log_commit error {}", workerid, e);
+                                        KVMsg::new_reply(KVMsgType::TransactionReturn, KVStatus::ServerError, &[])
+                                    }
+                                },
+                            }
+                        },
+                    };
+                    kv_debug!("worker #{}: handled TXN", workerid);
+                    reply
+                },
+                KVMsgType::Scan | KVMsgType::PrefixScan => {
+                    let (return_type, name) = match msg.msgtype {
+                        KVMsgType::Scan => (KVMsgType::ScanReturn, "SCAN"),
+                        _ => (KVMsgType::PrefixScanReturn, "PREFIX SCAN"),
+                    };
+                    /* both become a walk from a start key, bounded by an end key or a prefix */
+                    let scan = match msg.msgtype {
+                        KVMsgType::Scan => KVScan::from_bytes(&msg.msg)
+                            .map(|scan| (scan.start, scan.end, KVKey::from_slice(&[]).unwrap(), scan.limit)),
+                        _ => KVPrefixScan::from_bytes(&msg.msg)
+                            .map(|scan| (scan.cursor.map_or(scan.prefix, |cursor| cursor.max(scan.prefix)), None, scan.prefix, scan.limit)),
+                    };
+                    let reply = match scan {
+                        Err(KvError::KeyTooLong(_)) => KVMsg::new_reply(return_type, KVStatus::KeyTooLong, &[]),
+                        Err(_) => KVMsg::new_reply(return_type, KVStatus::BadRequest, &[]),
+                        Ok((start, end, prefix, limit)) => {
+                            let limit = scan_limit(limit);
+                            match log_scan(store, &start, end.as_ref(), prefix.as_bytes(), limit, scan_bytes(connection.max_frame_size, limit)) {
+                                Ok((pairs, cursor)) => KVMsg::new_reply(return_type, KVStatus::Ok, &KVScanPage { pairs, cursor }.to_bytes()),
+                                Err(e) => {
+                                    eprintln!("worker #{}: log_scan error {}", workerid, e);
+                                    KVMsg::new_reply(return_type, KVStatus::ServerError, &[])
+                                }
+                            }
+                        },
+                    };
+                    kv_debug!("worker #{}: handled {}", workerid, name);
+                    reply
+                },
+                KVMsgType::Exists => {
+                    let status = match KVKey::from_bytes(&msg.msg) {
+                        Err(KvError::KeyTooLong(_)) => KVStatus::KeyTooLong,
+                        Err(_) => KVStatus::BadRequest,
+                        Ok(key) => match log_exists(store, &key) {
+                            Ok(true) => KVStatus::Ok,
+                            Ok(false) => KVStatus::NotFound,
+                            Err(e) => {
+                                eprintln!("worker #{}: log_exists error {}", workerid, e);
+                                KVStatus::ServerError
+                            }
+                        },
+                    };
+                    kv_debug!("worker #{}: handled EXISTS", workerid);
+                    KVMsg::new_reply(KVMsgType::ExistsReturn, status, &[])
+                },
+                KVMsgType::Count => {
+                    let reply = match KVKey::from_bytes(&msg.msg) {
+                        Err(KvError::KeyTooLong(_)) => KVMsg::new_reply(KVMsgType::CountReturn, KVStatus::KeyTooLong, &[]),
+                        Err(_) => KVMsg::new_reply(KVMsgType::CountReturn, KVStatus::BadRequest, &[]),
+                        Ok(prefix) => match log_count(store, prefix.as_bytes()) {
+                            Ok(count) => KVMsg::new_reply(KVMsgType::CountReturn, KVStatus::Ok, &(count as u64).to_le_bytes()),
+                            Err(e) => {
+                                eprintln!("worker #{}: log_count error {}", workerid, e);
+                                KVMsg::new_reply(KVMsgType::CountReturn, KVStatus::ServerError, &[])
+                            }
+                        },
+                    };
+                    kv_debug!("worker #{}: handled COUNT", workerid);
+                    reply
+                },
+                KVMsgType::StrLen => {
+                    let reply = match KVKey::from_bytes(&msg.msg) {
+                        Err(KvError::KeyTooLong(_)) => KVMsg::new_reply(KVMsgType::StrLenReturn, KVStatus::KeyTooLong, &[]),
+                        Err(_) => KVMsg::new_reply(KVMsgType::StrLenReturn, KVStatus::BadRequest, &[]),
+                        Ok(key) => match log_strlen(store, &key) {
+                            Ok(Some(len)) => KVMsg::new_reply(KVMsgType::StrLenReturn, KVStatus::Ok, &len.to_le_bytes()),
+                            Ok(None) => KVMsg::new_reply(KVMsgType::StrLenReturn, KVStatus::NotFound, &[]),
+                            Err(e) => {
+                                eprintln!("worker #{}: log_strlen error {}", workerid, e);
+                                KVMsg::new_reply(KVMsgType::StrLenReturn, KVStatus::ServerError, &[])
+                            }
+                        },
+                    };
+                    kv_debug!("worker #{}: handled STRLEN", workerid);
+                    reply
                 },
                 _ => {
-                    println!("worker #{}: received unknown msg type", workerid);
+                    kv_info!("worker #{}: received unexpected msg type {:?}", workerid, msg.msgtype);
+                    KVMsg::new_reply(KVMsgType::ErrorReturn, KVStatus::BadRequest, &[])
                 }
+            };
+
+            /* replies carry the id of their request so pipelining clients can match them */
+            reply.reqid = msg.reqid;
+            let msgtype = reply.msgtype;
+            if let Err(KvError::FrameTooLarge(len)) = connection.send_kvmsg(reply) {
+                /* nothing was sent, e.g. a MultiGet whose values add up past the limit */
+                eprintln!("worker #{}: reply of {} bytes too large", workerid, len);
+                let mut reply = KVMsg::new_reply(msgtype, KVStatus::FrameTooLarge, &[]);
+                reply.reqid = msg.reqid;
+                connection.send_kvmsg(reply)?;
             }
         }
     
@@ -185,6 +2198,437 @@ pub mod worker{
     }
 }
 
+pub mod compaction{
+    use std::{ffi::c_void, time::Duration};
+
+    use crate::{storage::LogStore, threading::kv_pthread_detach};
+
+    /// Data passed as arg to compaction_thread
+    pub struct CompactionData<'a>{
+        pub store: &'a mut LogStore,
+        pub interval: Duration,
+    }
+
+    /// start routine for the background compaction thread
+    pub extern "C" fn compaction_thread(arg: *mut c_void) -> *mut c_void{
+        kv_pthread_detach().unwrap();
+        let data = unsafe { Box::from_raw(arg as *mut CompactionData)};
+
+        loop {
+            std::thread::sleep(data.interval);
+            if let Err(e) = data.store.compact(false) {
+                eprintln!("compaction_thread: compact error {}", e);
+            }
+        }
+    }
+}
+
+pub mod expiry{
+    use std::{ffi::c_void, time::Duration};
+
+    use crate::{storage::LogStore, threading::kv_pthread_detach};
+
+    /// Data passed as arg to expiry_thread
+    pub struct ExpiryData<'a>{
+        pub store: &'a mut LogStore,
+        pub interval: Duration,
+    }
+
+    /// start routine for the thread dropping expired keys nobody reads
+    pub extern "C" fn expiry_thread(arg: *mut c_void) -> *mut c_void{
+        kv_pthread_detach().unwrap();
+        let data = unsafe { Box::from_raw(arg as *mut ExpiryData)};
+
+        loop {
+            std::thread::sleep(data.interval);
+            match data.store.expire() {
+                Ok(0) => (),
+                Ok(n) => kv_info!("expiry_thread: expired {} keys", n),
+                Err(e) => eprintln!("expiry_thread: expire error {}", e),
+            }
+        }
+    }
+}
+
+pub mod flushing{
+    use std::{ffi::c_void, time::Duration};
+
+    use crate::{storage::LogStore, threading::kv_pthread_detach};
+
+    /// Data passed as arg to flush_thread
+    pub struct FlushData<'a>{
+        pub store: &'a mut LogStore,
+        pub interval: Duration,
+    }
+
+    /// start routine for the group commit thread of Durability::Batched
+    pub extern "C" fn flush_thread(arg: *mut c_void) -> *mut c_void{
+        kv_pthread_detach().unwrap();
+        let data = unsafe { Box::from_raw(arg as *mut FlushData)};
+
+        loop {
+            std::thread::sleep(data.interval);
+            if let Err(e) = data.store.flush() {
+                eprintln!("flush_thread: flush error {}", e);
+            }
+        }
+    }
+}
+
+pub mod registry{
+    use std::{os::fd::RawFd, time::SystemTime};
+
+    use kv_shared::semaphores::{kv_mutex_init, kv_mutex_lock, kv_mutex_unlock};
+    use nix::{errno::Errno, libc::pthread_mutex_t};
+
+    /// A connection a worker is serving
+    #[derive(Copy, Clone, Debug)]
+    pub struct ClientInfo {
+        pub fd: RawFd,
+        /// process and user on the other end of the socket, 0 if unknown
+        pub pid: i32,
+        pub uid: u32,
+        /// protocol version agreed in the handshake
+        pub version: u16,
+        pub since: SystemTime,
+        pub requests: u64,
+    }
+
+    /// What a worker is doing and has done
+    #[derive(Copy, Clone, Debug, Default)]
+    pub struct WorkerStatus {
+        /// connection being served, None while waiting for one
+        pub client: Option<ClientInfo>,
+        /// connections served since start
+        pub connections: u64,
+        /// requests handled since start
+        pub requests: u64,
+    }
+
+    /// Status of every worker, kept up to date by the workers for the admin console
+    pub struct Registry {
+        workers: Vec<WorkerStatus>,
+        started: SystemTime,
+        mtx: pthread_mutex_t,
+    }
+
+    impl Registry {
+        pub fn new(workers: usize) -> Result<Self, Errno> {
+            Ok(Self {
+                workers: vec![WorkerStatus::default(); workers],
+                started: SystemTime::now(),
+                mtx: kv_mutex_init()?,
+            })
+        }
+
+        /// When the server started
+        pub fn started(&self) -> SystemTime {
+            self.started
+        }
+
+        /// Worker took on client
+        pub fn connected(&mut self, worker: u64, client: ClientInfo) -> Result<(), Errno> {
+            kv_mutex_lock(&mut self.mtx)?;
+            let status = &mut self.workers[worker as usize];
+            status.client = Some(client);
+            status.connections += 1;
+            kv_mutex_unlock(&mut self.mtx)
+        }
+
+        /// Worker received a request from its client
+        pub fn request(&mut self, worker: u64) -> Result<(), Errno> {
+            kv_mutex_lock(&mut self.mtx)?;
+            let status = &mut self.workers[worker as usize];
+            status.requests += 1;
+            if let Some(client) = status.client.as_mut() {
+                client.requests += 1;
+            }
+            kv_mutex_unlock(&mut self.mtx)
+        }
+
+        /// Worker is done with its client, if it had one
+        pub fn disconnected(&mut self, worker: u64) -> Result<(), Errno> {
+            kv_mutex_lock(&mut self.mtx)?;
+            self.workers[worker as usize].client = None;
+            kv_mutex_unlock(&mut self.mtx)
+        }
+
+        /// Copy of every worker's status, in worker id order
+        pub fn workers(&mut self) -> Result<Vec<WorkerStatus>, Errno> {
+            kv_mutex_lock(&mut self.mtx)?;
+            let workers = self.workers.clone();
+            kv_mutex_unlock(&mut self.mtx)?;
+            Ok(workers)
+        }
+    }
+}
+
+pub mod console{
+    use std::{ffi::c_void, os::fd::OwnedFd, path::PathBuf, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+    use kv_shared::ringbuffer::FdRingBuffer;
+    use nix::{errno::Errno, libc::pthread_t, unistd::read};
+
+    use crate::{logging::{LogLevel, log_level, set_log_level}, registry::Registry, storage::LogStore, threading::{kv_pthread_create, kv_pthread_detach}};
+
+    const HELP: &str = "commands:
+  stats               keys, log size, connections and requests
+  workers             what each worker thread is doing
+  clients             open connections
+  compact             compact the log in the background
+  snapshot [dir]      copy the log to dir, ./kv-snapshots/<unix time> by default
+  loglevel [level]    show or set the log level: error, info or debug
+  shutdown            stop the server";
+
+    /// What the server the console runs in is made of, borrowed for one command
+    pub struct ConsoleData<'a>{
+        pub store: &'a mut LogStore,
+        pub registry: &'a mut Registry,
+        pub rbuf: &'a mut FdRingBuffer,
+    }
+
+    /// What the polling loop should do after a command
+    #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+    pub enum ConsoleAction {
+        Continue,
+        Shutdown,
+    }
+
+    /// Command too slow for the polling loop, run on a thread of its own
+    enum Job {
+        Compact,
+        Snapshot(PathBuf),
+    }
+
+    /// Data passed as arg to job_thread
+    struct JobData<'a>{
+        store: &'a mut LogStore,
+        job: Job,
+    }
+
+    /// start routine for a console command that runs in the background
+    extern "C" fn job_thread(arg: *mut c_void) -> *mut c_void{
+        kv_pthread_detach().unwrap();
+        let data = unsafe { Box::from_raw(arg as *mut JobData)};
+
+        match &data.job {
+            Job::Compact => match data.store.compact(true) {
+                Ok(true) => println!("console: compaction done"),
+                Ok(false) => println!("console: nothing compacted, another compaction is running or ran out of segment ids"),
+                Err(e) => eprintln!("console: compact error {}", e),
+            },
+            Job::Snapshot(dir) => match data.store.snapshot(dir) {
+                Ok(n) => println!("console: snapshot of {} segments in {}", n, dir.display()),
+                Err(Errno::EBUSY) => println!("console: no snapshot, a compaction or snapshot is running"),
+                Err(e) => eprintln!("console: snapshot to {} error {}", dir.display(), e),
+            },
+        }
+        std::ptr::null_mut()
+    }
+
+    /// Start job on a detached thread so the polling loop keeps serving
+    fn spawn_job(data: &mut ConsoleData, job: Job) -> Result<(), Errno> {
+        let mut thread = 0 as pthread_t;
+        let arg = Box::into_raw(Box::new(JobData { store: &mut *data.store, job })) as *mut c_void;
+        if let Err(e) = kv_pthread_create(&mut thread, job_thread, arg) {
+            drop(unsafe { Box::from_raw(arg as *mut JobData) });
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Bytes read from stdin that do not make a whole line yet
+    #[derive(Default)]
+    pub struct ConsoleInput {
+        buf: Vec<u8>,
+    }
+
+    impl ConsoleInput {
+        /// Read what is ready on fd, returns the lines it completed, None at end of input
+        pub fn read_lines(&mut self, fd: &OwnedFd) -> Result<Option<Vec<String>>, Errno> {
+            let mut chunk = [0u8; 1024];
+            let n = match read(fd, &mut chunk) {
+                Ok(0) => return Ok(None),
+                Ok(n) => n,
+                Err(Errno::EINTR) | Err(Errno::EAGAIN) => return Ok(Some(Vec::new())),
+                Err(e) => return Err(e),
+            };
+            self.buf.extend(&chunk[..n]);
+
+            let mut lines = Vec::new();
+            while let Some(end) = self.buf.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=end).collect();
+                lines.push(String::from_utf8_lossy(&line).trim().to_string());
+            }
+            Ok(Some(lines))
+        }
+    }
+
+    /// Run one line typed on the console
+    pub fn run_command(data: &mut ConsoleData, line: &str) -> ConsoleAction {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result = match words.as_slice() {
+            [] => Ok(()),
+            ["help"] => {
+                println!("{}", HELP);
+                Ok(())
+            },
+            ["stats"] => stats(data),
+            ["workers"] => workers(data),
+            ["clients"] => clients(data),
+            ["compact"] => {
+                println!("console: compacting in the background");
+                spawn_job(data, Job::Compact)
+            },
+            ["snapshot"] | ["snapshot", _] => {
+                let dir = match words.get(1) {
+                    Some(dir) => PathBuf::from(dir),
+                    None => PathBuf::from("./kv-snapshots").join(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs().to_string()),
+                };
+                println!("console: snapshot to {} in the background", dir.display());
+                spawn_job(data, Job::Snapshot(dir))
+            },
+            ["loglevel"] => {
+                println!("console: log level {}", log_level().name());
+                Ok(())
+            },
+            ["loglevel", name] => {
+                match LogLevel::from_name(name) {
+                    Some(level) => {
+                        set_log_level(level);
+                        println!("console: log level {}", level.name());
+                    },
+                    None => println!("console: unknown log level '{}', use error, info or debug", name),
+                }
+                Ok(())
+            },
+            ["shutdown"] => {
+                println!("console: shutting down");
+                return ConsoleAction::Shutdown;
+            },
+            [name, ..] => {
+                println!("console: unknown command or arguments '{}', help lists the commands", name);
+                Ok(())
+            },
+        };
+        if let Err(e) = result {
+            eprintln!("console: {} error {}", words[0], e);
+        }
+        ConsoleAction::Continue
+    }
+
+    /// Duration as 1h02m03s, 2m03s or 3s
+    fn format_duration(d: Duration) -> String {
+        let secs = d.as_secs();
+        match secs {
+            0..60 => format!("{}s", secs),
+            60..3600 => format!("{}m{:02}s", secs / 60, secs % 60),
+            _ => format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60),
+        }
+    }
+
+    fn since(time: SystemTime) -> String {
+        format_duration(time.elapsed().unwrap_or_default())
+    }
+
+    fn stats(data: &mut ConsoleData) -> Result<(), Errno> {
+        let store = data.store.stats()?;
+        let workers = data.registry.workers()?;
+        let open = workers.iter().filter(|w| w.client.is_some()).count();
+        let connections: u64 = workers.iter().map(|w| w.connections).sum();
+        let requests: u64 = workers.iter().map(|w| w.requests).sum();
+
+        println!("uptime       {}", since(data.registry.started()));
+        println!("keys         {} ({} with expiry)", store.keys, store.expiring);
+        println!("log          {} segments, {} bytes, {} live{}", store.segments, store.total_bytes, store.live_bytes,
+            if store.compacting { ", compacting" } else { "" });
+        println!("last version {}", store.last_version);
+        println!("durability   {:?}", data.store.durability());
+        println!("connections  {} open, {} waiting, {} since start", open, data.rbuf.len(), connections);
+        println!("requests     {}", requests);
+        println!("log level    {}", log_level().name());
+        Ok(())
+    }
+
+    fn workers(data: &mut ConsoleData) -> Result<(), Errno> {
+        for (id, worker) in data.registry.workers()?.iter().enumerate() {
+            let state = match &worker.client {
+                Some(client) => format!("serving fd {} for {}", client.fd, since(client.since)),
+                None => "idle".to_string(),
+            };
+            println!("worker #{}: {}, {} connections, {} requests", id, state, worker.connections, worker.requests);
+        }
+        Ok(())
+    }
+
+    fn clients(data: &mut ConsoleData) -> Result<(), Errno> {
+        let workers = data.registry.workers()?;
+        let mut open: usize = 0;
+        for (id, worker) in workers.iter().enumerate() {
+            if let Some(client) = &worker.client {
+                println!("fd {}: pid {}, uid {}, protocol {}, worker #{}, connected {}, {} requests",
+                    client.fd, client.pid, client.uid, client.version, id, since(client.since), client.requests);
+                open += 1;
+            }
+        }
+        let waiting = data.rbuf.len();
+        println!("{} open, {} waiting for a worker", open, waiting);
+        Ok(())
+    }
+}
+
+pub mod logging{
+    use std::sync::atomic::{AtomicU8, Ordering};
+
+    /// How much the server prints, errors are always printed
+    #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+    #[repr(u8)]
+    pub enum LogLevel {
+        Error = 0,
+        /// connections, background work and admin commands
+        Info = 1,
+        /// every request and poll event as well
+        Debug = 2,
+    }
+
+    impl LogLevel {
+        pub fn from_name(name: &str) -> Option<Self> {
+            match name {
+                "error" => Some(LogLevel::Error),
+                "info" => Some(LogLevel::Info),
+                "debug" => Some(LogLevel::Debug),
+                _ => None,
+            }
+        }
+
+        pub fn name(self) -> &'static str {
+            match self {
+                LogLevel::Error => "error",
+                LogLevel::Info => "info",
+                LogLevel::Debug => "debug",
+            }
+        }
+    }
+
+    static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Debug as u8);
+
+    pub fn set_log_level(level: LogLevel) {
+        LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+    }
+
+    pub fn log_level() -> LogLevel {
+        match LOG_LEVEL.load(Ordering::Relaxed) {
+            0 => LogLevel::Error,
+            1 => LogLevel::Info,
+            _ => LogLevel::Debug,
+        }
+    }
+
+    pub fn log_enabled(level: LogLevel) -> bool {
+        level <= log_level()
+    }
+}
+
 pub mod signaling{
     use std::{ffi::c_void, os::fd::{RawFd}};
     use nix::libc::{ c_int, write};
@@ -199,14 +2643,11 @@ pub mod signaling{
         /* write one bit to self-pipe */
         if signal == Signal::SIGINT {
             unsafe {
-                match PIPE_WRITE_FD {
-                    Some(fd) => {
-                        let mut nbytes: isize = 0;
-                        while nbytes == 0 {
-                            nbytes = write(fd, &1u8 as *const u8 as *const c_void, 1);
-                        }                        
-                    },
-                    None => {}
+                if let Some(fd) = PIPE_WRITE_FD {
+                    let mut nbytes: isize = 0;
+                    while nbytes == 0 {
+                        nbytes = write(fd, &1u8 as *const u8 as *const c_void, 1);
+                    }
                 }
             }
         }
@@ -1,3 +1,4 @@
+use kv_shared::io::DEFAULT_MAX_FRAME_SIZE;
 use kv_shared::ringbuffer::FdRingBuffer;
 use nix::errno::Errno;
 use nix::fcntl::OFlag;
@@ -5,13 +6,20 @@ use nix::libc::{pthread_t};
 use nix::poll::PollTimeout;
 use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags};
 use nix::sys::signal::{signal, SigHandler, Signal};
-use nix::unistd::{close, pipe2, unlink};
+use nix::unistd::{close, dup, pipe2, unlink};
 use std::collections::HashMap;
-use std::os::fd::{ AsRawFd, OwnedFd, RawFd };
+use std::os::fd::{ AsRawFd, OwnedFd };
 use std::os::raw::c_void;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use kv_server::{self, accept_connection, open_socket};
+use kv_server::{self, accept_connection, kv_debug, open_socket};
+use kv_server::compaction::{CompactionData, compaction_thread};
+use kv_server::console::{ConsoleAction, ConsoleData, ConsoleInput, run_command};
+use kv_server::expiry::{ExpiryData, expiry_thread};
+use kv_server::flushing::{FlushData, flush_thread};
+use kv_server::registry::Registry;
+use kv_server::storage::{Durability, LogStore, StoreConfig};
 use kv_server::threading::{kv_pthread_create};
 use kv_server::worker::{WorkerData, worker_thread};
 use kv_server::polling::{PollInterests, kv_epoll_add};
@@ -23,19 +31,70 @@ fn main() -> Result<(), Errno> {
 
     /* init work ring buffer */
     let mut rbuf = FdRingBuffer::init();
+
+    /* open data log, replaying its segments to rebuild the key index */
+    const SEGMENT_MAX_BYTES: u64 = 64 << 20;
+    const DURABILITY: Durability = Durability::Always;
+    let config = StoreConfig {
+        dir: PathBuf::from("./kv-data"),
+        segment_max_bytes: SEGMENT_MAX_BYTES,
+        durability: DURABILITY,
+    };
+    let mut store = match LogStore::open(config){
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("server: LogStore::open {}", e);
+            return Err(e);
+        }
+    };
+    println!("server: recovered {} keys from {} segments", store.len(), store.segment_count());
     
     /* init worker thread pool */
     const THREAD_POOL_SIZE: usize = 5;
+    const MAX_FRAME_SIZE: usize = DEFAULT_MAX_FRAME_SIZE;
+    let mut registry = Registry::new(THREAD_POOL_SIZE)?;
     for i in 0..THREAD_POOL_SIZE {
         let mut thread = 0 as pthread_t;
         let data = Box::new(WorkerData {
             id: i as u64,
             rbuf: &mut rbuf,
+            store: &mut store,
+            registry: &mut registry,
+            max_frame_size: MAX_FRAME_SIZE,
         });
         let arg = Box::into_raw(data) as *mut c_void;
         kv_pthread_create(&mut thread, worker_thread, arg).unwrap();
     }
 
+    /* start group commit for batched durability */
+    if let Durability::Batched(interval) = store.durability() {
+        let mut flush_thread_id = 0 as pthread_t;
+        let data = Box::new(FlushData {
+            store: &mut store,
+            interval,
+        });
+        let arg = Box::into_raw(data) as *mut c_void;
+        kv_pthread_create(&mut flush_thread_id, flush_thread, arg).unwrap();
+    }
+
+    /* start background compaction */
+    let mut compaction_thread_id = 0 as pthread_t;
+    let data = Box::new(CompactionData {
+        store: &mut store,
+        interval: Duration::from_secs(10),
+    });
+    let arg = Box::into_raw(data) as *mut c_void;
+    kv_pthread_create(&mut compaction_thread_id, compaction_thread, arg).unwrap();
+
+    /* start sweeping expired keys */
+    let mut expiry_thread_id = 0 as pthread_t;
+    let data = Box::new(ExpiryData {
+        store: &mut store,
+        interval: Duration::from_secs(1),
+    });
+    let arg = Box::into_raw(data) as *mut c_void;
+    kv_pthread_create(&mut expiry_thread_id, expiry_thread, arg).unwrap();
+
     /* init listening socket */
     let socket_path = Path::new("./kv.sock");
     let socket_fd = match open_socket(socket_path){
@@ -65,15 +124,21 @@ fn main() -> Result<(), Errno> {
     kv_epoll_add(&epoll, &socket_fd, EpollFlags::EPOLLIN | EpollFlags::EPOLLET, PollInterests::ListeningSocket).unwrap();
     kv_epoll_add(&epoll, &pipe_rd_fd, EpollFlags::EPOLLIN | EpollFlags::EPOLLET, PollInterests::SIGINT).unwrap();
 
-    /* todo: accept commands from stdin */
+    /* accept admin commands from stdin, unless it is something epoll cannot watch like /dev/null */
+    let stdin_fd = dup(std::io::stdin())?;
+    let mut console_input = ConsoleInput::default();
+    match kv_epoll_add(&epoll, &stdin_fd, EpollFlags::EPOLLIN, PollInterests::TerminalInput) {
+        Ok(()) => println!("server: admin console on stdin, help lists the commands"),
+        Err(e) => println!("server: no admin console, stdin cannot be polled: {}", e),
+    }
+    interestfds.insert(PollInterests::TerminalInput as u64, &stdin_fd);
 
     /* start polling */
     let mut events = [EpollEvent::empty()];
-    let mut poll_results_num: usize = 0;
                 
     'polling: loop {
-        println!("server: polling");
-        poll_results_num = match epoll.wait(&mut events, PollTimeout::NONE){
+        kv_debug!("server: polling");
+        let poll_results_num = match epoll.wait(&mut events, PollTimeout::NONE){
             Ok(size) => size,
             Err(Errno::EINTR) => continue 'polling, /* todo: prevent polling msg from printing again? */
             Err(e) => {
@@ -82,14 +147,40 @@ fn main() -> Result<(), Errno> {
             }
         };
         
-        for event in events {
+        for event in &events[..poll_results_num] {
             if event.data() == PollInterests::ListeningSocket as u64 {
-                println!("server: got a {:?} event on Listening Socket", event.events());
+                kv_debug!("server: got a {:?} event on Listening Socket", event.events());
                 let listenfd = interestfds.get(&(PollInterests::ListeningSocket as u64)).unwrap();
                 accept_connection(listenfd, &mut rbuf).unwrap();
-                println!("server: put accepted connection to buffer");
+                kv_debug!("server: put accepted connection to buffer");
+            } else if event.data() == PollInterests::TerminalInput as u64 {
+                let stdinfd = interestfds.get(&(PollInterests::TerminalInput as u64)).unwrap();
+                let lines = match console_input.read_lines(stdinfd) {
+                    Ok(Some(lines)) => lines,
+                    Ok(None) => {
+                        /* stdin stays readable at its end, stop watching it */
+                        println!("server: stdin closed, admin console off");
+                        epoll.delete(stdinfd)?;
+                        continue;
+                    },
+                    Err(e) => {
+                        eprintln!("server: read stdin {}, admin console off", e);
+                        epoll.delete(stdinfd)?;
+                        continue;
+                    }
+                };
+                for line in lines {
+                    let mut console = ConsoleData {
+                        store: &mut store,
+                        registry: &mut registry,
+                        rbuf: &mut rbuf,
+                    };
+                    if run_command(&mut console, &line) == ConsoleAction::Shutdown {
+                        break 'polling;
+                    }
+                }
             } else if event.data() == PollInterests::SIGINT as u64 {
-                println!("server: got a {:?} event on SIGINT", event.events());
+                kv_debug!("server: got a {:?} event on SIGINT", event.events());
                 break 'polling;
             } else {
                 println!("Got an unhandled {:?} with data {:?}", event.events(), event.data());
@@ -0,0 +1,100 @@
+use std::os::fd::AsRawFd;
+use std::os::raw::c_void;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use kv_server::registry::Registry;
+use kv_server::storage::{Durability, LogStore, StoreConfig};
+use kv_server::threading::kv_pthread_create;
+use kv_server::worker::{WorkerData, worker_thread};
+use kv_shared::io::{DEFAULT_MAX_FRAME_SIZE, KVConnection, KVHello, KVKey, KVMsg, KVMsgType, KVPair, KVStatus, PROTOCOL_VERSION};
+use kv_shared::ringbuffer::FdRingBuffer;
+use nix::libc::pthread_t;
+use nix::sys::socket::{AddressFamily, MsgFlags, SockFlag, SockType, send, socketpair};
+
+/// A worker thread serving one end of a socket pair, returns the other end
+fn serve(name: &str) -> KVConnection {
+    let dir = std::env::temp_dir().join(format!("kv-protocol-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let store = Box::leak(Box::new(LogStore::open(StoreConfig {
+        dir: PathBuf::from(&dir),
+        durability: Durability::Never,
+        ..StoreConfig::default()
+    }).unwrap()));
+    let rbuf = Box::leak(Box::new(FdRingBuffer::init()));
+    let registry = Box::leak(Box::new(Registry::new(1).unwrap()));
+
+    let (ours, theirs) = socketpair(AddressFamily::Unix, SockType::Stream, None, SockFlag::SOCK_CLOEXEC).unwrap();
+    rbuf.put(theirs).unwrap();
+    let mut thread = 0 as pthread_t;
+    let data = Box::new(WorkerData {
+        id: 0,
+        rbuf,
+        store,
+        registry,
+        max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+    });
+    kv_pthread_create(&mut thread, worker_thread, Box::into_raw(data) as *mut c_void).unwrap();
+
+    KVConnection {
+        fd: ours,
+        max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        version: 0,
+        features: 0,
+        next_reqid: 0,
+        last_sendtime: Duration::ZERO,
+    }
+}
+
+#[test]
+fn v2_client_is_served() {
+    let mut connection = serve("v2");
+
+    /* a user-014 client offers exactly version 2 */
+    let hello = KVHello { min_version: 2, max_version: 2, features: 0 };
+    connection.send_kvmsg(KVMsg::new(KVMsgType::Hello, hello.to_bytes())).unwrap();
+    let reply = connection.recv_kvmsg().unwrap();
+    assert_eq!(reply.msgtype, KVMsgType::HelloReturn);
+    let (status, body) = reply.reply_status().unwrap();
+    assert_eq!(status, KVStatus::Ok);
+    assert_eq!(KVHello::from_bytes(body).unwrap().max_version, 2);
+    connection.version = 2;
+
+    let key = KVKey::new("old").unwrap();
+    let pair = KVPair::new(key, b"client".to_vec());
+    connection.send_kvmsg(KVMsg::new(KVMsgType::Set, pair.to_bytes())).unwrap();
+    let reply = connection.recv_kvmsg().unwrap();
+    assert_eq!(reply.msgtype, KVMsgType::SetReturn);
+    assert_eq!(reply.reply_status().unwrap().0, KVStatus::Ok);
+
+    /* a v2 GetReturn is the bare value, without the version ahead of it */
+    connection.send_kvmsg(KVMsg::new(KVMsgType::Get, key.to_bytes())).unwrap();
+    let reply = connection.recv_kvmsg().unwrap();
+    assert_eq!(reply.msgtype, KVMsgType::GetReturn);
+    assert_eq!(reply.reply_status().unwrap(), (KVStatus::Ok, &b"client"[..]));
+}
+
+#[test]
+fn undecodable_frame_reply_keeps_its_reqid() {
+    let mut connection = serve("badframe");
+    let hello = KVHello { min_version: PROTOCOL_VERSION, max_version: PROTOCOL_VERSION, features: 0 };
+    connection.send_kvmsg(KVMsg::new(KVMsgType::Hello, hello.to_bytes())).unwrap();
+    assert_eq!(connection.recv_kvmsg().unwrap().reply_status().unwrap().0, KVStatus::Ok);
+    connection.version = PROTOCOL_VERSION;
+
+    /* a whole header with request id 7, but a message type nobody knows */
+    let mut frame: Vec<u8> = Vec::new();
+    frame.extend(9999u32.to_le_bytes());
+    frame.extend(0u64.to_le_bytes());
+    frame.extend(0u32.to_le_bytes());
+    frame.extend(7u64.to_le_bytes());
+    frame.extend(0u64.to_le_bytes());
+    let mut bytes = frame.len().to_be_bytes().to_vec();
+    bytes.extend(&frame);
+    assert_eq!(send(connection.fd.as_raw_fd(), &bytes, MsgFlags::empty()).unwrap(), bytes.len());
+
+    let reply = connection.recv_kvmsg().unwrap();
+    assert_eq!(reply.msgtype, KVMsgType::ErrorReturn);
+    assert_eq!(reply.reqid, 7);
+    assert_eq!(reply.reply_status().unwrap().0, KVStatus::BadRequest);
+}
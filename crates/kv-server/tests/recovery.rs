@@ -0,0 +1,170 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use kv_server::storage::{crc32, Durability, LogStore, StoreConfig, RECORD_BATCH, RECORD_HEADER_LEN, RECORD_VERSIONED};
+use kv_shared::io::KVKey;
+
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("kv-recovery-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn open(dir: &Path) -> LogStore {
+    LogStore::open(StoreConfig {
+        dir: dir.to_path_buf(),
+        durability: Durability::Never,
+        ..StoreConfig::default()
+    })
+    .unwrap()
+}
+
+fn key(name: &str) -> KVKey {
+    KVKey::from_slice(name.as_bytes()).unwrap()
+}
+
+/// Write two keys, return the path and length of the only segment
+fn populate(dir: &Path) -> (PathBuf, u64) {
+    let mut store = open(dir);
+    store.set(&key("alpha"), b"one", 0).unwrap();
+    store.set(&key("beta"), b"two", 0).unwrap();
+    drop(store);
+    let segment = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "kvlog"))
+        .unwrap();
+    let len = fs::metadata(&segment).unwrap().len();
+    (segment, len)
+}
+
+fn append(path: &Path, bytes: &[u8]) {
+    OpenOptions::new().append(true).open(path).unwrap().write_all(bytes).unwrap();
+}
+
+/// Reopen, check the tail was cut back to len and both keys survived
+fn check_recovered(dir: &Path, segment: &Path, len: u64) {
+    let mut store = open(dir);
+    assert_eq!(fs::metadata(segment).unwrap().len(), len);
+    assert_eq!(store.get(&key("alpha")).unwrap().unwrap().0, b"one");
+    assert_eq!(store.get(&key("beta")).unwrap().unwrap().0, b"two");
+    store.set(&key("gamma"), b"three", 0).unwrap();
+    drop(store);
+
+    let mut store = open(dir);
+    assert_eq!(store.get(&key("gamma")).unwrap().unwrap().0, b"three");
+    drop(store);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn torn_tail_is_truncated() {
+    let dir = test_dir("torn");
+    let (segment, len) = populate(&dir);
+    let mut store = open(&dir);
+    store.set(&key("torn"), b"never finished", 0).unwrap();
+    drop(store);
+    let full = fs::metadata(&segment).unwrap().len();
+    OpenOptions::new().write(true).open(&segment).unwrap().set_len(full - 5).unwrap();
+
+    check_recovered(&dir, &segment, len);
+}
+
+#[test]
+fn garbage_tail_is_truncated() {
+    let dir = test_dir("garbage");
+    let (segment, len) = populate(&dir);
+    append(&segment, &[0xA5; 100]);
+
+    check_recovered(&dir, &segment, len);
+}
+
+#[test]
+fn huge_value_length_is_truncated() {
+    let dir = test_dir("huge");
+    let (segment, len) = populate(&dir);
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    header[5..9].copy_from_slice(&1u32.to_le_bytes());
+    header[9..17].copy_from_slice(&u64::MAX.to_le_bytes());
+    append(&segment, &header);
+
+    check_recovered(&dir, &segment, len);
+}
+
+#[test]
+fn huge_batch_op_length_is_truncated() {
+    let dir = test_dir("batch");
+    let (segment, len) = populate(&dir);
+
+    /* a batch record with a valid checksum whose one op claims a u64::MAX value */
+    let mut body: Vec<u8> = Vec::new();
+    body.extend(1000u64.to_le_bytes());
+    body.push(0);
+    body.extend(1u32.to_le_bytes());
+    body.extend(u64::MAX.to_le_bytes());
+    body.push(b'k');
+    let mut record: Vec<u8> = vec![RECORD_BATCH | RECORD_VERSIONED];
+    record.extend(0u32.to_le_bytes());
+    record.extend(((body.len() - 8) as u64).to_le_bytes());
+    record.extend(&body);
+    let mut bytes = crc32(&record).to_le_bytes().to_vec();
+    bytes.extend(&record);
+    append(&segment, &bytes);
+
+    check_recovered(&dir, &segment, len);
+}
+
+#[test]
+fn versions_survive_compaction() {
+    let dir = test_dir("versions");
+    let mut store = open(&dir);
+    store.set(&key("alpha"), b"one", 0).unwrap();
+    store.set(&key(""), b"empty", 0).unwrap();
+    store.set(&key("beta"), b"two", 0).unwrap();
+    assert!(store.delete(&key("beta")).unwrap());
+    assert!(store.compact(true).unwrap());
+    drop(store);
+
+    /* the delete that gave out version 4 is gone from the log, twice over */
+    let mut store = open(&dir);
+    assert!(store.compact(true).unwrap());
+    drop(store);
+
+    let mut store = open(&dir);
+    assert_eq!(store.get(&key("alpha")).unwrap().unwrap(), (b"one".to_vec(), 1));
+    assert_eq!(store.get(&key("")).unwrap().unwrap(), (b"empty".to_vec(), 2));
+    assert_eq!(store.get(&key("beta")).unwrap(), None);
+    assert_eq!(store.set(&key("gamma"), b"three", 0).unwrap(), 5);
+    drop(store);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn snapshot_writes_stay_in_the_snapshot() {
+    let dir = test_dir("snapshot-src");
+    let snap = test_dir("snapshot-dst");
+    let mut store = open(&dir);
+    store.set(&key("alpha"), b"one", 0).unwrap();
+    assert_eq!(store.snapshot(&snap).unwrap(), 1);
+    store.set(&key("beta"), b"two", 0).unwrap();
+    drop(store);
+
+    let mut copy = open(&snap);
+    assert_eq!(copy.get(&key("alpha")).unwrap().unwrap().0, b"one");
+    assert_eq!(copy.get(&key("beta")).unwrap(), None);
+    copy.set(&key("only-in-snapshot"), b"three", 0).unwrap();
+    drop(copy);
+
+    let mut store = open(&dir);
+    assert_eq!(store.get(&key("alpha")).unwrap().unwrap().0, b"one");
+    assert_eq!(store.get(&key("beta")).unwrap().unwrap().0, b"two");
+    assert_eq!(store.get(&key("only-in-snapshot")).unwrap(), None);
+    drop(store);
+
+    let mut copy = open(&snap);
+    assert_eq!(copy.get(&key("only-in-snapshot")).unwrap().unwrap().0, b"three");
+    drop(copy);
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&snap).unwrap();
+}
@@ -1,11 +1,77 @@
 
 pub mod io {
-    use std::{os::fd::{AsRawFd, OwnedFd},time::{Duration, SystemTime, UNIX_EPOCH}};
+    use std::{cmp::Ordering, fmt, hash::{Hash, Hasher}, os::fd::{AsRawFd, OwnedFd},time::{Duration, SystemTime, UNIX_EPOCH}};
 
     use nix::{errno::Errno, libc::size_t, sys::socket::{MsgFlags, recv, send}};
 
 
-    #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+    /// Wire protocol version spoken by this build.
+    /// 1: keys sent as 256 padded bytes plus a u64 length.
+    /// 2: keys sent as a u16 length followed by only the key bytes,
+    ///    connections open with a Hello/HelloReturn exchange.
+    /// 3: every message after the handshake carries a request id, echoed in its reply.
+    /// 4: GetReturn carries the value's version ahead of the value.
+    pub const PROTOCOL_VERSION: u16 = 4;
+    /// Oldest wire protocol version this build still speaks
+    pub const MIN_PROTOCOL_VERSION: u16 = 2;
+    /// First protocol version whose message header carries a request id. Hello and
+    /// HelloReturn are sent before a version is agreed so they never carry one, which
+    /// keeps the handshake readable by peers of any version
+    pub const REQID_SINCE: u16 = 3;
+    /// First protocol version whose GetReturn carries the value's version
+    pub const GET_VERSION_SINCE: u16 = 4;
+
+    /* feature bits agreed in the handshake */
+    pub const FEATURE_COMPRESSION: u64 = 0x01;
+    pub const FEATURE_PIPELINING: u64 = 0x02;
+    pub const FEATURE_AUTH: u64 = 0x04;
+
+    /// Body of Hello, and of HelloReturn after its status.
+    /// In Hello it is the range of versions and the features the client supports. A
+    /// successful HelloReturn holds the agreed version as both ends of the range and the
+    /// agreed features, a rejecting one holds the range the server supports.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct KVHello {
+        pub min_version: u16,
+        pub max_version: u16,
+        pub features: u64,
+    }
+
+    impl KVHello {
+        pub fn to_bytes(&self) -> Vec<u8> {
+            let mut bytes: Vec<u8> = Vec::with_capacity(12);
+            bytes.extend(&self.min_version.to_le_bytes());          // 2 bytes
+            bytes.extend(&self.max_version.to_le_bytes());          // 2 bytes
+            bytes.extend(&self.features.to_le_bytes());             // 8 bytes
+            bytes
+        }
+
+        pub fn from_bytes(bytes: &[u8]) -> Result<Self, KvError> {
+            if bytes.len() < 12 {
+                return Err(KvError::Protocol("hello shorter than its fields"));
+            }
+            Ok(Self {
+                min_version: u16::from_le_bytes(bytes[0..2].try_into().unwrap()),
+                max_version: u16::from_le_bytes(bytes[2..4].try_into().unwrap()),
+                features: u64::from_le_bytes(bytes[4..12].try_into().unwrap()),
+            })
+        }
+
+        /// Highest version and the features both sides support, ours is this side's hello
+        pub fn negotiate(&self, theirs: &KVHello) -> Result<(u16, u64), KvError> {
+            let version = std::cmp::min(self.max_version, theirs.max_version);
+            if version < self.min_version || version < theirs.min_version {
+                return Err(KvError::Incompatible {
+                    ours: (self.min_version, self.max_version),
+                    theirs: (theirs.min_version, theirs.max_version),
+                });
+            }
+            Ok((version, self.features & theirs.features))
+        }
+    }
+
+    /// A key of up to MAX_LEN arbitrary bytes, ordered and compared byte-wise
+    #[derive(Clone, Copy)]
     pub struct KVKey {
         data: [u8; 256],
         len: usize,
@@ -14,41 +80,564 @@ pub mod io {
     impl KVKey {
         pub const MAX_LEN: usize = 256;
 
-        pub fn new(s: &str) -> Result<Self, ()> {
-            if s.len() > Self::MAX_LEN {
-                return Err(());
+        pub fn new(s: &str) -> Result<Self, KvError> {
+            Self::from_slice(s.as_bytes())
+        }
+
+        pub fn from_slice(bytes: &[u8]) -> Result<Self, KvError> {
+            if bytes.len() > Self::MAX_LEN {
+                return Err(KvError::KeyTooLong(bytes.len()));
             }
 
             let mut data = [0u8; Self::MAX_LEN];
-            data[..s.len()].copy_from_slice(s.as_bytes());
+            data[..bytes.len()].copy_from_slice(bytes);
             Ok(Self { 
-                data: data, 
-                len: s.len() 
+                data, 
+                len: bytes.len() 
+            })
+        }
+
+        /// The key as text, None if it is not valid utf-8
+        pub fn as_str(&self) -> Option<&str> {
+            std::str::from_utf8(self.as_bytes()).ok()
+        }
+
+        pub fn as_bytes(&self) -> &[u8] {
+            &self.data[..self.len]
+        }
+
+        /// Number of bytes to_bytes produces
+        pub fn encoded_len(&self) -> usize {
+            2 + self.len
+        }
+
+        pub fn to_bytes(&self) -> Vec<u8> {
+            let mut bytes: Vec<u8> = Vec::with_capacity(self.encoded_len());
+            bytes.extend(&(self.len as u16).to_le_bytes());             // 2 bytes
+            bytes.extend(self.as_bytes());                              // 0 - MAX_LEN bytes
+            bytes
+        }
+
+        /// Decode a key from the front of bytes, anything after it is left for the caller
+        pub fn from_bytes(bytes: &[u8]) -> Result<Self, KvError> {
+            if bytes.len() < 2 {
+                return Err(KvError::Protocol("key shorter than its length"));
+            }
+            let len = u16::from_le_bytes(bytes[0..2].try_into().unwrap()) as usize;
+            if len > Self::MAX_LEN {
+                return Err(KvError::KeyTooLong(len));
+            }
+            if bytes.len() - 2 < len {
+                return Err(KvError::Protocol("key shorter than its length"));
+            }
+            Self::from_slice(&bytes[2..2 + len])
+        }
+    }
+
+    impl PartialEq for KVKey {
+        fn eq(&self, other: &Self) -> bool {
+            self.as_bytes() == other.as_bytes()
+        }
+    }
+
+    impl Eq for KVKey {}
+
+    impl Hash for KVKey {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.as_bytes().hash(state);
+        }
+    }
+
+    impl PartialOrd for KVKey {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for KVKey {
+        fn cmp(&self, other: &Self) -> Ordering {
+            self.as_bytes().cmp(other.as_bytes())
+        }
+    }
+
+    impl fmt::Debug for KVKey {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "KVKey(\"{}\")", self.as_bytes().escape_ascii())
+        }
+    }
+
+    /// Largest value the server will store
+    pub const MAX_VALUE_LEN: usize = 64 << 20;
+
+    /// Most pairs the server puts in one page of a scan, a limit of 0 asks for this many
+    pub const MAX_SCAN_LIMIT: u32 = 1000;
+
+    const PAIR_HAS_TTL: u8 = 0x01;
+    const PAIR_HAS_CAS: u8 = 0x02;
+
+    /// A key and value with optional metadata, the payload of Set
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct KVPair {
+        pub key: KVKey,
+        pub value: Vec<u8>,
+        /// seconds until the pair expires
+        pub ttl: Option<u64>,
+        /// per-pair option bits, none are defined yet
+        pub flags: u32,
+        /// only store the pair if the key's current version matches
+        pub cas: Option<u64>,
+    }
+
+    impl KVPair {
+        pub fn new(key: KVKey, value: Vec<u8>) -> Self {
+            Self {
+                key,
+                value,
+                ttl: None,
+                flags: 0,
+                cas: None,
+            }
+        }
+
+        pub fn to_bytes(&self) -> Vec<u8> {
+            let mut present: u8 = 0;
+            if self.ttl.is_some() { present |= PAIR_HAS_TTL; }
+            if self.cas.is_some() { present |= PAIR_HAS_CAS; }
+
+            let mut bytes: Vec<u8> = Vec::with_capacity(self.key.encoded_len() + 29 + self.value.len());
+            bytes.extend(self.key.to_bytes());                              // 2 - MAX_LEN+2 bytes
+            bytes.push(present);                                            // 1 byte
+            bytes.extend(&self.flags.to_le_bytes());                        // 4 bytes
+            if let Some(ttl) = self.ttl {
+                bytes.extend(&ttl.to_le_bytes());                           // 8 bytes
+            }
+            if let Some(cas) = self.cas {
+                bytes.extend(&cas.to_le_bytes());                           // 8 bytes
+            }
+            bytes.extend(&(self.value.len() as u64).to_le_bytes());         // 8 bytes
+            bytes.extend(&self.value);                                      // 0 - ? bytes
+            bytes
+        }
+
+        pub fn from_bytes(bytes: &[u8]) -> Result<Self, KvError> {
+            let key = KVKey::from_bytes(bytes)?;
+            let mut rest = &bytes[key.encoded_len()..];
+
+            if rest.len() < 5 {
+                return Err(KvError::Protocol("pair shorter than its header"));
+            }
+            let present = rest[0];
+            let flags = u32::from_le_bytes(rest[1..5].try_into().unwrap());
+            rest = &rest[5..];
+
+            let read_u64 = |rest: &mut &[u8]| -> Result<u64, KvError> {
+                if rest.len() < 8 {
+                    return Err(KvError::Protocol("pair shorter than its header"));
+                }
+                let val = u64::from_le_bytes(rest[0..8].try_into().unwrap());
+                *rest = &rest[8..];
+                Ok(val)
+            };
+            let ttl = if present & PAIR_HAS_TTL != 0 { Some(read_u64(&mut rest)?) } else { None };
+            let cas = if present & PAIR_HAS_CAS != 0 { Some(read_u64(&mut rest)?) } else { None };
+            let val_len = read_u64(&mut rest)? as usize;
+
+            if rest.len() != val_len {
+                return Err(KvError::Protocol("pair value length does not match its body"));
+            }
+
+            Ok(Self {
+                key,
+                value: rest.to_vec(),
+                ttl,
+                flags,
+                cas,
             })
         }
+    }
+
+    /* read a little-endian u32 or u64 off the front of rest */
+    fn take_u32(rest: &mut &[u8], what: &'static str) -> Result<u32, KvError> {
+        if rest.len() < 4 {
+            return Err(KvError::Protocol(what));
+        }
+        let val = u32::from_le_bytes(rest[0..4].try_into().unwrap());
+        *rest = &rest[4..];
+        Ok(val)
+    }
+
+    fn take_u64(rest: &mut &[u8], what: &'static str) -> Result<u64, KvError> {
+        if rest.len() < 8 {
+            return Err(KvError::Protocol(what));
+        }
+        let val = u64::from_le_bytes(rest[0..8].try_into().unwrap());
+        *rest = &rest[8..];
+        Ok(val)
+    }
+
+    /// Body of MultiGet and MultiDelete: a u32 count then that many keys
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct KVKeyList {
+        pub keys: Vec<KVKey>,
+    }
+
+    impl KVKeyList {
+        pub fn to_bytes(&self) -> Vec<u8> {
+            let len: usize = self.keys.iter().map(KVKey::encoded_len).sum();
+            let mut bytes: Vec<u8> = Vec::with_capacity(4 + len);
+            bytes.extend(&(self.keys.len() as u32).to_le_bytes());     // 4 bytes
+            for key in &self.keys {
+                bytes.extend(key.to_bytes());                           // 2 - MAX_LEN+2 bytes each
+            }
+            bytes
+        }
+
+        pub fn from_bytes(bytes: &[u8]) -> Result<Self, KvError> {
+            let mut rest = bytes;
+            let count = take_u32(&mut rest, "key list shorter than its count")? as usize;
+            /* every key takes at least 2 bytes, don't trust count further than that */
+            let mut keys = Vec::with_capacity(count.min(rest.len() / 2));
+            for _ in 0..count {
+                let key = KVKey::from_bytes(rest)?;
+                rest = &rest[key.encoded_len()..];
+                keys.push(key);
+            }
+            if !rest.is_empty() {
+                return Err(KvError::Protocol("key list longer than its count"));
+            }
+            Ok(Self { keys })
+        }
+    }
+
+    /// Body of MultiSet: a u32 count then each pair behind its u64 length
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct KVPairList {
+        pub pairs: Vec<KVPair>,
+    }
+
+    impl KVPairList {
+        pub fn to_bytes(&self) -> Vec<u8> {
+            let mut bytes: Vec<u8> = Vec::new();
+            bytes.extend(&(self.pairs.len() as u32).to_le_bytes());    // 4 bytes
+            for pair in &self.pairs {
+                let pair_bytes = pair.to_bytes();
+                bytes.extend(&(pair_bytes.len() as u64).to_le_bytes()); // 8 bytes each
+                bytes.extend(pair_bytes);                               // 0 - ? bytes each
+            }
+            bytes
+        }
+
+        pub fn from_bytes(bytes: &[u8]) -> Result<Self, KvError> {
+            let mut rest = bytes;
+            let count = take_u32(&mut rest, "pair list shorter than its count")? as usize;
+            let mut pairs = Vec::with_capacity(count.min(rest.len() / 8));
+            for _ in 0..count {
+                let len = take_u64(&mut rest, "pair list shorter than its count")? as usize;
+                if rest.len() < len {
+                    return Err(KvError::Protocol("pair list shorter than its count"));
+                }
+                pairs.push(KVPair::from_bytes(&rest[..len])?);
+                rest = &rest[len..];
+            }
+            if !rest.is_empty() {
+                return Err(KvError::Protocol("pair list longer than its count"));
+            }
+            Ok(Self { pairs })
+        }
+    }
+
+    /// Payload of MultiGetReturn, MultiSetReturn and MultiDeleteReturn after their status:
+    /// a u32 count then for each key, in request order, its status and a value behind its
+    /// u64 length. Only MultiGet hits carry a non-empty value.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct KVResultList {
+        pub results: Vec<(KVStatus, Vec<u8>)>,
+    }
+
+    impl KVResultList {
+        pub fn to_bytes(&self) -> Vec<u8> {
+            let mut bytes: Vec<u8> = Vec::new();
+            bytes.extend(&(self.results.len() as u32).to_le_bytes());  // 4 bytes
+            for (status, value) in &self.results {
+                bytes.extend(&(*status as u32).to_le_bytes());          // 4 bytes each
+                bytes.extend(&(value.len() as u64).to_le_bytes());      // 8 bytes each
+                bytes.extend(value);                                    // 0 - ? bytes each
+            }
+            bytes
+        }
+
+        pub fn from_bytes(bytes: &[u8]) -> Result<Self, KvError> {
+            let mut rest = bytes;
+            let count = take_u32(&mut rest, "result list shorter than its count")? as usize;
+            let mut results = Vec::with_capacity(count.min(rest.len() / 12));
+            for _ in 0..count {
+                let status = take_u32(&mut rest, "result list shorter than its count")?;
+                let status = KVStatus::from_u32(status).ok_or(KvError::Protocol("unknown reply status"))?;
+                let len = take_u64(&mut rest, "result list shorter than its count")? as usize;
+                if rest.len() < len {
+                    return Err(KvError::Protocol("result list shorter than its count"));
+                }
+                results.push((status, rest[..len].to_vec()));
+                rest = &rest[len..];
+            }
+            if !rest.is_empty() {
+                return Err(KvError::Protocol("result list longer than its count"));
+            }
+            Ok(Self { results })
+        }
+    }
+
+    const TXN_OP_SET: u8 = 0;
+    const TXN_OP_DELETE: u8 = 1;
+
+    /// One write of a transaction
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum KVTxnOp {
+        Set(KVPair),
+        Delete(KVKey),
+    }
+
+    /// Body of Transaction: version guards, then the writes applied together if every
+    /// guard holds. A guard holds when its key is at exactly its version, 0 meaning the
+    /// key is not present.
+    ///   guard count u32, then per guard: key, version u64
+    ///   op count u32, then per op: kind u8, then a Set's pair behind its u64 length
+    ///   or a Delete's key
+    #[derive(Clone, Debug, Default, PartialEq, Eq)]
+    pub struct KVTxn {
+        pub guards: Vec<(KVKey, u64)>,
+        pub ops: Vec<KVTxnOp>,
+    }
+
+    impl KVTxn {
+        pub fn to_bytes(&self) -> Vec<u8> {
+            let mut bytes: Vec<u8> = Vec::new();
+            bytes.extend(&(self.guards.len() as u32).to_le_bytes());   // 4 bytes
+            for (key, version) in &self.guards {
+                bytes.extend(key.to_bytes());                           // 2 - MAX_LEN+2 bytes each
+                bytes.extend(&version.to_le_bytes());                   // 8 bytes each
+            }
+            bytes.extend(&(self.ops.len() as u32).to_le_bytes());      // 4 bytes
+            for op in &self.ops {
+                match op {
+                    KVTxnOp::Set(pair) => {
+                        let pair_bytes = pair.to_bytes();
+                        bytes.push(TXN_OP_SET);                         // 1 byte
+                        bytes.extend(&(pair_bytes.len() as u64).to_le_bytes()); // 8 bytes
+                        bytes.extend(pair_bytes);                       // 0 - ? bytes
+                    },
+                    KVTxnOp::Delete(key) => {
+                        bytes.push(TXN_OP_DELETE);                      // 1 byte
+                        bytes.extend(key.to_bytes());                   // 2 - MAX_LEN+2 bytes
+                    },
+                }
+            }
+            bytes
+        }
+
+        pub fn from_bytes(bytes: &[u8]) -> Result<Self, KvError> {
+            let mut rest = bytes;
+            let count = take_u32(&mut rest, "transaction shorter than its guard count")? as usize;
+            let mut guards = Vec::with_capacity(count.min(rest.len() / 10));
+            for _ in 0..count {
+                let key = KVKey::from_bytes(rest)?;
+                rest = &rest[key.encoded_len()..];
+                let version = take_u64(&mut rest, "transaction shorter than its guard count")?;
+                guards.push((key, version));
+            }
+
+            let count = take_u32(&mut rest, "transaction shorter than its op count")? as usize;
+            let mut ops = Vec::with_capacity(count.min(rest.len() / 3));
+            for _ in 0..count {
+                if rest.is_empty() {
+                    return Err(KvError::Protocol("transaction shorter than its op count"));
+                }
+                let kind = rest[0];
+                rest = &rest[1..];
+                match kind {
+                    TXN_OP_SET => {
+                        let len = take_u64(&mut rest, "transaction shorter than its op count")? as usize;
+                        if rest.len() < len {
+                            return Err(KvError::Protocol("transaction shorter than its op count"));
+                        }
+                        ops.push(KVTxnOp::Set(KVPair::from_bytes(&rest[..len])?));
+                        rest = &rest[len..];
+                    },
+                    TXN_OP_DELETE => {
+                        let key = KVKey::from_bytes(rest)?;
+                        rest = &rest[key.encoded_len()..];
+                        ops.push(KVTxnOp::Delete(key));
+                    },
+                    _ => return Err(KvError::Protocol("unknown transaction op")),
+                }
+            }
+            if !rest.is_empty() {
+                return Err(KvError::Protocol("transaction longer than its op count"));
+            }
+            Ok(Self { guards, ops })
+        }
+    }
+
+    /// Body of Incr and Decr: the key, then the i64 to add to or subtract from its value
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct KVIncr {
+        pub key: KVKey,
+        pub delta: i64,
+    }
+
+    impl KVIncr {
+        pub fn to_bytes(&self) -> Vec<u8> {
+            let mut bytes: Vec<u8> = Vec::with_capacity(self.key.encoded_len() + 8);
+            bytes.extend(self.key.to_bytes());                              // 2 - MAX_LEN+2 bytes
+            bytes.extend(&self.delta.to_le_bytes());                        // 8 bytes
+            bytes
+        }
+
+        pub fn from_bytes(bytes: &[u8]) -> Result<Self, KvError> {
+            let key = KVKey::from_bytes(bytes)?;
+            let rest = &bytes[key.encoded_len()..];
+            match rest.try_into() {
+                Ok(delta) => Ok(Self { key, delta: i64::from_le_bytes(delta) }),
+                Err(_) => Err(KvError::Protocol("increment delta is not 8 bytes")),
+            }
+        }
+    }
+
+    /* optional key: a present byte, then the key if it is 1 */
+    fn put_opt_key(bytes: &mut Vec<u8>, key: &Option<KVKey>) {
+        match key {
+            Some(key) => {
+                bytes.push(1);
+                bytes.extend(key.to_bytes());
+            },
+            None => bytes.push(0),
+        }
+    }
+
+    fn take_opt_key(rest: &mut &[u8], what: &'static str) -> Result<Option<KVKey>, KvError> {
+        if rest.is_empty() {
+            return Err(KvError::Protocol(what));
+        }
+        let present = rest[0];
+        *rest = &rest[1..];
+        match present {
+            0 => Ok(None),
+            1 => {
+                let key = KVKey::from_bytes(rest)?;
+                *rest = &rest[key.encoded_len()..];
+                Ok(Some(key))
+            },
+            _ => Err(KvError::Protocol(what)),
+        }
+    }
+
+    /// Body of Scan: keys from start, up to but not including end if there is one, in
+    /// byte order, at most limit pairs to a page.
+    ///   start key, end as an optional key, limit u32
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct KVScan {
+        pub start: KVKey,
+        pub end: Option<KVKey>,
+        pub limit: u32,
+    }
+
+    impl KVScan {
+        pub fn to_bytes(&self) -> Vec<u8> {
+            let mut bytes: Vec<u8> = Vec::new();
+            bytes.extend(self.start.to_bytes());                            // 2 - MAX_LEN+2 bytes
+            put_opt_key(&mut bytes, &self.end);                             // 1 - MAX_LEN+3 bytes
+            bytes.extend(&self.limit.to_le_bytes());                        // 4 bytes
+            bytes
+        }
+
+        pub fn from_bytes(bytes: &[u8]) -> Result<Self, KvError> {
+            let start = KVKey::from_bytes(bytes)?;
+            let mut rest = &bytes[start.encoded_len()..];
+            let end = take_opt_key(&mut rest, "scan shorter than its fields")?;
+            let limit = take_u32(&mut rest, "scan shorter than its fields")?;
+            if !rest.is_empty() {
+                return Err(KvError::Protocol("scan longer than its fields"));
+            }
+            Ok(Self { start, end, limit })
+        }
+    }
+
+    /// Body of PrefixScan: keys beginning with prefix in byte order, from cursor if this
+    /// continues an earlier page, at most limit pairs to a page.
+    ///   prefix key, cursor as an optional key, limit u32
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct KVPrefixScan {
+        pub prefix: KVKey,
+        pub cursor: Option<KVKey>,
+        pub limit: u32,
+    }
+
+    impl KVPrefixScan {
+        pub fn to_bytes(&self) -> Vec<u8> {
+            let mut bytes: Vec<u8> = Vec::new();
+            bytes.extend(self.prefix.to_bytes());                           // 2 - MAX_LEN+2 bytes
+            put_opt_key(&mut bytes, &self.cursor);                          // 1 - MAX_LEN+3 bytes
+            bytes.extend(&self.limit.to_le_bytes());                        // 4 bytes
+            bytes
+        }
 
-        pub fn as_str(&self) -> &str {
-            std::str::from_utf8(&self.data[..self.len]).unwrap()
+        pub fn from_bytes(bytes: &[u8]) -> Result<Self, KvError> {
+            let prefix = KVKey::from_bytes(bytes)?;
+            let mut rest = &bytes[prefix.encoded_len()..];
+            let cursor = take_opt_key(&mut rest, "prefix scan shorter than its fields")?;
+            let limit = take_u32(&mut rest, "prefix scan shorter than its fields")?;
+            if !rest.is_empty() {
+                return Err(KvError::Protocol("prefix scan longer than its fields"));
+            }
+            Ok(Self { prefix, cursor, limit })
         }
+    }
+
+    /// Payload of ScanReturn and PrefixScanReturn: one page of pairs in key order, and the
+    /// key the next page starts at, None once the scan is done.
+    ///   cursor as an optional key, count u32, then per pair: key, val_len u64, value
+    #[derive(Clone, Debug, Default, PartialEq, Eq)]
+    pub struct KVScanPage {
+        pub pairs: Vec<(KVKey, Vec<u8>)>,
+        pub cursor: Option<KVKey>,
+    }
 
+    impl KVScanPage {
         pub fn to_bytes(&self) -> Vec<u8> {
             let mut bytes: Vec<u8> = Vec::new();
-            bytes.extend(&self.data);
-            bytes.extend(&(self.len as u64).to_le_bytes());
+            put_opt_key(&mut bytes, &self.cursor);                          // 1 - MAX_LEN+3 bytes
+            bytes.extend(&(self.pairs.len() as u32).to_le_bytes());        // 4 bytes
+            for (key, value) in &self.pairs {
+                bytes.extend(key.to_bytes());                               // 2 - MAX_LEN+2 bytes each
+                bytes.extend(&(value.len() as u64).to_le_bytes());          // 8 bytes each
+                bytes.extend(value);                                        // 0 - ? bytes each
+            }
             bytes
         }
 
-        pub fn from_bytes(bytes: &[u8]) -> Result<Self, ()> {
-            if bytes.len() < Self::MAX_LEN + 8 {
-                return Err(());
+        pub fn from_bytes(bytes: &[u8]) -> Result<Self, KvError> {
+            let mut rest = bytes;
+            let cursor = take_opt_key(&mut rest, "scan page shorter than its fields")?;
+            let count = take_u32(&mut rest, "scan page shorter than its count")? as usize;
+            let mut pairs = Vec::with_capacity(count.min(rest.len() / 10));
+            for _ in 0..count {
+                let key = KVKey::from_bytes(rest)?;
+                rest = &rest[key.encoded_len()..];
+                let len = take_u64(&mut rest, "scan page shorter than its count")? as usize;
+                if rest.len() < len {
+                    return Err(KvError::Protocol("scan page shorter than its count"));
+                }
+                pairs.push((key, rest[..len].to_vec()));
+                rest = &rest[len..];
             }
-            let data: [u8;Self::MAX_LEN] = bytes[..Self::MAX_LEN].try_into().unwrap();
-            let len = u64::from_le_bytes(bytes[Self::MAX_LEN..Self::MAX_LEN+8].try_into().unwrap()) as usize;
-            Ok(Self { data: data, len: len })
+            if !rest.is_empty() {
+                return Err(KvError::Protocol("scan page longer than its count"));
+            }
+            Ok(Self { pairs, cursor })
         }
     }
 
-    #[derive(Copy, Clone)]
+    #[derive(Copy, Clone, PartialEq, Eq, Debug)]
     #[repr(u32)]
     pub enum KVMsgType {
         Get = 0,
@@ -57,11 +646,61 @@ pub mod io {
         GetReturn = 3,
         SetReturn = 4,
         DeleteReturn = 5,
+        /// reply to a request the server could not decode, body is a KVStatus
+        ErrorReturn = 6,
+        Hello = 7,
+        HelloReturn = 8,
+        /// body is a KVKeyList
+        MultiGet = 9,
+        /// body is a KVPairList
+        MultiSet = 10,
+        /// body is a KVKeyList
+        MultiDelete = 11,
+        /// replies to the multi-key requests, payload is a KVResultList
+        MultiGetReturn = 12,
+        MultiSetReturn = 13,
+        MultiDeleteReturn = 14,
+        /// body is a KVTxn
+        Transaction = 15,
+        /// payload is the u64 version given to the transaction's writes
+        TransactionReturn = 16,
+        /// body is a KVPair whose cas holds the version the key must be at
+        CompareAndSet = 17,
+        /// payload is the u64 version given to the value
+        CompareAndSetReturn = 18,
+        /// body is a KVIncr
+        Incr = 19,
+        /// payload is the i64 value after the increment
+        IncrReturn = 20,
+        /// body is a KVIncr
+        Decr = 21,
+        /// payload is the i64 value after the decrement
+        DecrReturn = 22,
+        /// body is a KVScan
+        Scan = 23,
+        /// payload is a KVScanPage
+        ScanReturn = 24,
+        /// body is a KVPrefixScan
+        PrefixScan = 25,
+        /// payload is a KVScanPage
+        PrefixScanReturn = 26,
+        /// body is a key
+        Exists = 27,
+        /// status Ok if the key is present, NotFound if not, no payload
+        ExistsReturn = 28,
+        /// body is a key used as a prefix, the empty key counts every key
+        Count = 29,
+        /// payload is the u64 number of keys
+        CountReturn = 30,
+        /// body is a key
+        StrLen = 31,
+        /// payload is the u64 length of the value
+        StrLenReturn = 32,
     }
 
     impl KVMsgType{
         /* convert u32 to KVMsgType */
-        fn from_u32(val: u32) -> Result<Self, ()>{
+        fn from_u32(val: u32) -> Result<Self, KvError>{
             match val {
                 0 => Ok(KVMsgType::Get),
                 1 => Ok(KVMsgType::Set),
@@ -69,14 +708,151 @@ pub mod io {
                 3 => Ok(KVMsgType::GetReturn),
                 4 => Ok(KVMsgType::SetReturn),
                 5 => Ok(KVMsgType::DeleteReturn),
-                _ => Err(()),
+                6 => Ok(KVMsgType::ErrorReturn),
+                7 => Ok(KVMsgType::Hello),
+                8 => Ok(KVMsgType::HelloReturn),
+                9 => Ok(KVMsgType::MultiGet),
+                10 => Ok(KVMsgType::MultiSet),
+                11 => Ok(KVMsgType::MultiDelete),
+                12 => Ok(KVMsgType::MultiGetReturn),
+                13 => Ok(KVMsgType::MultiSetReturn),
+                14 => Ok(KVMsgType::MultiDeleteReturn),
+                15 => Ok(KVMsgType::Transaction),
+                16 => Ok(KVMsgType::TransactionReturn),
+                17 => Ok(KVMsgType::CompareAndSet),
+                18 => Ok(KVMsgType::CompareAndSetReturn),
+                19 => Ok(KVMsgType::Incr),
+                20 => Ok(KVMsgType::IncrReturn),
+                21 => Ok(KVMsgType::Decr),
+                22 => Ok(KVMsgType::DecrReturn),
+                23 => Ok(KVMsgType::Scan),
+                24 => Ok(KVMsgType::ScanReturn),
+                25 => Ok(KVMsgType::PrefixScan),
+                26 => Ok(KVMsgType::PrefixScanReturn),
+                27 => Ok(KVMsgType::Exists),
+                28 => Ok(KVMsgType::ExistsReturn),
+                29 => Ok(KVMsgType::Count),
+                30 => Ok(KVMsgType::CountReturn),
+                31 => Ok(KVMsgType::StrLen),
+                32 => Ok(KVMsgType::StrLenReturn),
+                _ => Err(KvError::UnknownMsgType(val)),
             }
         }
     }
     
+    /// Outcome of a request, first 4 bytes of every reply body
+    #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+    #[repr(u32)]
+    pub enum KVStatus {
+        Ok = 0,
+        NotFound = 1,
+        KeyTooLong = 2,
+        ValueTooLarge = 3,
+        BadRequest = 4,
+        ServerError = 5,
+        Busy = 6,
+        FrameTooLarge = 7,
+        Incompatible = 8,
+        /// a version guard did not hold, nothing was written
+        Conflict = 9,
+        /// Incr or Decr on a value that is not a decimal i64
+        NotInteger = 10,
+        /// Incr or Decr would take the value past the range of i64
+        Overflow = 11,
+    }
+
+    impl KVStatus {
+        /* convert u32 to KVStatus */
+        fn from_u32(val: u32) -> Option<Self> {
+            match val {
+                0 => Some(KVStatus::Ok),
+                1 => Some(KVStatus::NotFound),
+                2 => Some(KVStatus::KeyTooLong),
+                3 => Some(KVStatus::ValueTooLarge),
+                4 => Some(KVStatus::BadRequest),
+                5 => Some(KVStatus::ServerError),
+                6 => Some(KVStatus::Busy),
+                7 => Some(KVStatus::FrameTooLarge),
+                8 => Some(KVStatus::Incompatible),
+                9 => Some(KVStatus::Conflict),
+                10 => Some(KVStatus::NotInteger),
+                11 => Some(KVStatus::Overflow),
+                _ => None,
+            }
+        }
+    }
+
+    impl fmt::Display for KVStatus {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let s = match self {
+                KVStatus::Ok => "ok",
+                KVStatus::NotFound => "key not found",
+                KVStatus::KeyTooLong => "key too long",
+                KVStatus::ValueTooLarge => "value too large",
+                KVStatus::BadRequest => "bad request",
+                KVStatus::ServerError => "server error",
+                KVStatus::Busy => "server busy",
+                KVStatus::FrameTooLarge => "frame too large",
+                KVStatus::Incompatible => "incompatible protocol version",
+                KVStatus::Conflict => "version check failed",
+                KVStatus::NotInteger => "value is not an integer",
+                KVStatus::Overflow => "integer overflow",
+            };
+            write!(f, "{}", s)
+        }
+    }
+
+    /// Errors of the kv client, server and wire protocol
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum KvError {
+        /// the server answered with a status other than Ok
+        Status(KVStatus),
+        /// a message could not be decoded
+        Protocol(&'static str),
+        /// a message type this side does not know
+        UnknownMsgType(u32),
+        /// a frame announced more bytes than the connection accepts
+        FrameTooLarge(usize),
+        /// a key longer than KVKey::MAX_LEN
+        KeyTooLong(usize),
+        /// user supplied text that could not be parsed
+        InvalidInput(&'static str),
+        /// the two sides share no protocol version, each given as (min, max)
+        Incompatible { ours: (u16, u16), theirs: (u16, u16) },
+        /// socket or file error
+        Io(Errno),
+    }
+
+    impl fmt::Display for KvError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                KvError::Status(status) => write!(f, "{}", status),
+                KvError::Protocol(what) => write!(f, "malformed message: {}", what),
+                KvError::UnknownMsgType(val) => write!(f, "unknown message type {}", val),
+                KvError::FrameTooLarge(len) => write!(f, "frame of {} bytes is too large", len),
+                KvError::KeyTooLong(len) => write!(f, "key of {} bytes is longer than {}", len, KVKey::MAX_LEN),
+                KvError::InvalidInput(what) => write!(f, "invalid input: {}", what),
+                KvError::Incompatible { ours, theirs } => write!(f,
+                    "incompatible peer: it speaks protocol versions {}-{}, this build speaks {}-{}",
+                    theirs.0, theirs.1, ours.0, ours.1),
+                KvError::Io(e) => write!(f, "io error: {}", e),
+            }
+        }
+    }
+
+    impl std::error::Error for KvError {}
+
+    impl From<Errno> for KvError {
+        fn from(e: Errno) -> Self {
+            KvError::Io(e)
+        }
+    }
+
     pub struct KVMsg{
         pub msgtype: KVMsgType,
         pub sendtime: Duration,
+        /// chosen by the client, echoed by the server in the reply, 0 when unused
+        pub reqid: u64,
         pub msg: Vec<u8>,
     }
     
@@ -86,62 +862,142 @@ pub mod io {
             .duration_since(UNIX_EPOCH)
             .unwrap();
             Self {
-                msgtype: msgtype, 
+                msgtype, 
                 sendtime: t,
-                msg: msg,
+                reqid: 0,
+                msg,
             }
         }
     
-        pub fn to_bytes(&self) -> Vec<u8> {
-            let mut bytes: Vec<u8> = Vec::new();
+        /// Build a reply, the body is status followed by payload
+        pub fn new_reply(msgtype: KVMsgType, status: KVStatus, payload: &[u8]) -> Self{
+            let mut body: Vec<u8> = Vec::with_capacity(4 + payload.len());
+            body.extend(&(status as u32).to_le_bytes());
+            body.extend(payload);
+            Self::new(msgtype, body)
+        }
+
+        /// Split a reply body into its status and payload
+        pub fn reply_status(&self) -> Result<(KVStatus, &[u8]), KvError> {
+            if self.msg.len() < 4 {
+                return Err(KvError::Protocol("reply shorter than its status"));
+            }
+            let status = u32::from_le_bytes(self.msg[0..4].try_into().unwrap());
+            match KVStatus::from_u32(status) {
+                Some(status) => Ok((status, &self.msg[4..])),
+                None => Err(KvError::Protocol("unknown reply status")),
+            }
+        }
+
+        /// Header length of a message on a connection that agreed version, 0 before the handshake
+        pub fn header_len(version: u16) -> usize {
+            match version >= REQID_SINCE {
+                true => 32,
+                false => 24,
+            }
+        }
+
+        /// Encode for a connection that agreed version, the request id is left out below REQID_SINCE
+        pub fn to_bytes(&self, version: u16) -> Vec<u8> {
+            let mut bytes: Vec<u8> = Vec::with_capacity(Self::header_len(version) + self.msg.len());
             bytes.extend(&(self.msgtype as u32).to_le_bytes());         // 4 bytes
             bytes.extend(&self.sendtime.as_secs().to_le_bytes());       // 8 bytes
             bytes.extend(&self.sendtime.subsec_nanos().to_le_bytes());  // 4 bytes
+            if version >= REQID_SINCE {
+                bytes.extend(&self.reqid.to_le_bytes());                // 8 bytes
+            }
             bytes.extend(&(self.msg.len() as u64).to_le_bytes());       // 8 bytes
             bytes.extend(&self.msg);                                    // 0 - ? bytes 
             bytes
         }
         
-        pub fn from_bytes(bytes: &[u8]) -> Result<Self, ()> {
+        /// Request id in the header of an encoded message, 0 if the header is cut short
+        /// or version has no ids. Lets a frame that does not decode still be answered
+        pub fn reqid_of(bytes: &[u8], version: u16) -> u64 {
+            match version >= REQID_SINCE && bytes.len() >= 24 {
+                true => u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+                false => 0,
+            }
+        }
+
+        /// Decode a message from a connection that agreed version
+        pub fn from_bytes(bytes: &[u8], version: u16) -> Result<Self, KvError> {
+            let header_len = Self::header_len(version);
             /* missing bytes, less than minimum */
-            if bytes.len() < 24 {
-                return Err(());
+            if bytes.len() < header_len {
+                return Err(KvError::Protocol("message shorter than its header"));
             }
             
             let msgtype = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
             let secs = u64::from_le_bytes(bytes[4..12].try_into().unwrap());
             let nanos = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
-            let msglen = u64::from_le_bytes(bytes[16..24].try_into().unwrap()) as usize;
+            let reqid = match version >= REQID_SINCE {
+                true => u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+                false => 0,
+            };
+            let msglen = u64::from_le_bytes(bytes[header_len - 8..header_len].try_into().unwrap()) as usize;
             
             /* missing bytes from msg field */
-            if bytes.len() < 24 + msglen {
-                return Err(());
+            if bytes.len() - header_len < msglen {
+                return Err(KvError::Protocol("message body shorter than its length"));
+            }
+            if nanos >= 1_000_000_000 {
+                return Err(KvError::Protocol("sendtime nanoseconds out of range"));
             }
-            let msg: Vec<u8> = bytes[24..24+msglen].to_vec();
+            let msg: Vec<u8> = bytes[header_len..header_len+msglen].to_vec();
             
             Ok(KVMsg { 
-                msgtype: KVMsgType::from_u32(msgtype).unwrap(), 
+                msgtype: KVMsgType::from_u32(msgtype)?, 
                 sendtime: Duration::new(secs, nanos), 
-                msg: msg,
+                reqid,
+                msg,
             })
             
         }
     } 
 
+    /// Default limit on a single frame: the largest value plus room for key and headers
+    pub const DEFAULT_MAX_FRAME_SIZE: size_t = MAX_VALUE_LEN + (64 << 10);
+
     pub struct KVConnection{
         pub fd: OwnedFd,
-        pub mtu: size_t,
+        /// largest frame, in bytes, sent or accepted on this connection
+        pub max_frame_size: size_t,
+        /// protocol version agreed in the handshake, 0 before it. Decides the header layout of frames
+        pub version: u16,
+        /// FEATURE_* bits agreed in the handshake
+        pub features: u64,
+        /// request id given to the next request sent with send_request
+        pub next_reqid: u64,
+        /// sendtime of the last request sent with send_request, for timing round trips
+        pub last_sendtime: Duration,
     }
     
     impl KVConnection{
+
+        /// Stamp msg with a fresh request id and send it, returning the id.
+        /// Below REQID_SINCE frames carry no id, the request is sent with and returns 0
+        pub fn send_request(&mut self, mut msg: KVMsg) -> Result<u64, KvError>{
+            if self.version >= REQID_SINCE {
+                self.next_reqid = self.next_reqid.wrapping_add(1).max(1);
+                msg.reqid = self.next_reqid;
+            }
+            let reqid = msg.reqid;
+            self.last_sendtime = msg.sendtime;
+            self.send_kvmsg(msg)?;
+            Ok(reqid)
+        }
         
         /// Ensures full send of KVMsg over KVConnection
-        pub fn send_kvmsg(&mut self, msg: KVMsg) -> Result<(), Errno>{
+        pub fn send_kvmsg(&mut self, msg: KVMsg) -> Result<(), KvError>{
             
-            let msg_bytes = msg.to_bytes();
+            let msg_bytes = msg.to_bytes(self.version);
             
             /* send msg length over */
             let msg_len = msg_bytes.len();
+            if msg_len > self.max_frame_size {
+                return Err(KvError::FrameTooLarge(msg_len));
+            }
             let len_buf = msg_len.to_be_bytes() as [u8; 8];
             let mut nbytes_len_sent: usize = 0;
             while nbytes_len_sent < 8 {
@@ -149,7 +1005,7 @@ pub mod io {
                     Ok(n) => nbytes_len_sent += n,
                     Err(e) => {
                         eprintln!("io::send_kvmsg send msg_len error: {}", e);
-                        return Err(e);
+                        return Err(KvError::Io(e));
                     }
                 }
             }
@@ -161,7 +1017,7 @@ pub mod io {
                     Ok(n) => nbytes_msg_sent += n,
                     Err(e) => {
                         eprintln!("io::send_kvmsg send msg_bytes error: {}", e);
-                        return Err(e);
+                        return Err(KvError::Io(e));
                     }
                 }
             }
@@ -169,40 +1025,52 @@ pub mod io {
             Ok(())
         }
 
-        /// Ensures full recv of KVMsg over KVConnection
-        pub fn recv_kvmsg(&mut self) -> Result<KVMsg, Errno>{
+        /// Ensures full recv of KVMsg over KVConnection.
+        /// A frame that fails to decode is consumed whole, so the connection stays usable,
+        /// except for FrameTooLarge after which the stream is out of step and must be closed.
+        pub fn recv_kvmsg(&mut self) -> Result<KVMsg, KvError>{
+            let frame = self.recv_frame()?;
+            KVMsg::from_bytes(&frame, self.version)
+        }
+
+        /// Ensures full recv of one frame over KVConnection, left encoded
+        pub fn recv_frame(&mut self) -> Result<Vec<u8>, KvError>{
         
             /* receive msg length */ 
             let mut len_buf= [0u8;8]; /* expecting usize */
             let mut nbytes_len_recvd: usize = 0;
             while nbytes_len_recvd < len_buf.len() {
                 match recv(self.fd.as_raw_fd(), &mut len_buf[nbytes_len_recvd..], MsgFlags::empty()){
-                    Ok(0) => return Err(Errno::ECONNRESET),
+                    Ok(0) => return Err(KvError::Io(Errno::ECONNRESET)),
                     Ok(n) => nbytes_len_recvd += n,
                     Err(e) => {
                         eprintln!("io::recv_kvmsg recv msg_len error: {}", e);
-                        return Err(e);
+                        return Err(KvError::Io(e));
                     }
                 }
             }
             let msg_len = u64::from_be_bytes(len_buf) as usize;
+
+            /* refuse before allocating, the frame body is left unread */
+            if msg_len > self.max_frame_size {
+                return Err(KvError::FrameTooLarge(msg_len));
+            }
             
             /* receive msg */
             let mut buf = vec![0u8; msg_len];
             let mut nbytes_recvd: usize = 0;
             while nbytes_recvd < msg_len {
                 match recv(self.fd.as_raw_fd(), &mut buf[nbytes_recvd..], MsgFlags::empty()){
-                    Ok(0) => return Err(Errno::ECONNRESET),
+                    Ok(0) => return Err(KvError::Io(Errno::ECONNRESET)),
                     Ok(n) => nbytes_recvd += n,
                     Err(e) => {
                         eprintln!("io::recv_all recv msg[len] error: {}", e);
-                        return Err(e);
+                        return Err(KvError::Io(e));
                     }
                 }
             }
         
-            let result = KVMsg::from_bytes(&buf).unwrap();
-            Ok(result)
+            Ok(buf)
         }
         
     }
@@ -245,9 +1113,9 @@ pub mod ringbuffer {
                 head: 0,
                 tail: 0,
                 mask: 0xFFF,
-                mtx: mtx,
-                items: items,
-                spaces: spaces,
+                mtx,
+                items,
+                spaces,
             }
         }
     
@@ -280,6 +1148,18 @@ pub mod ringbuffer {
 
             Some(fd)
         }
+
+        /// Number of fds waiting to be taken
+        pub fn len(&mut self) -> usize {
+            kv_mutex_lock(&mut self.mtx).unwrap();
+            let len = self.head - self.tail;
+            kv_mutex_unlock(&mut self.mtx).unwrap();
+            len
+        }
+
+        pub fn is_empty(&mut self) -> bool {
+            self.len() == 0
+        }
     }
 
 
@@ -287,7 +1167,7 @@ pub mod ringbuffer {
 }
 
 pub mod semaphores{
-    use nix::{errno::Errno, libc::{pthread_mutex_init, pthread_mutex_lock, pthread_mutex_t, pthread_mutex_unlock, sem_init, sem_post, sem_t, sem_wait}};
+    use nix::{errno::Errno, libc::{pthread_cond_broadcast, pthread_cond_init, pthread_cond_t, pthread_cond_wait, pthread_mutex_init, pthread_mutex_lock, pthread_mutex_t, pthread_mutex_unlock, sem_init, sem_post, sem_t, sem_wait}};
 
 
     /// Wrapper for libc::phread_mutex_init()
@@ -318,6 +1198,34 @@ pub mod semaphores{
         Ok(())
     }
     
+    /// Wrapper for libc::pthread_cond_init()
+    pub fn kv_cond_init() -> Result<pthread_cond_t, Errno> {
+        let mut cond: pthread_cond_t = unsafe { std::mem::zeroed() };
+        let res = unsafe {pthread_cond_init(&mut cond, std::ptr::null())};
+        if res != 0 {
+            return Err(Errno::from_raw(res));
+        } 
+        Ok(cond)
+    }
+
+    /// Wrapper for libc::pthread_cond_wait(), mtx must be locked by the caller
+    pub fn kv_cond_wait(cond: &mut pthread_cond_t, mtx: &mut pthread_mutex_t) -> Result<(), Errno> {
+        let res = unsafe {pthread_cond_wait(cond, mtx)};
+        if res != 0 {
+            return Err(Errno::from_raw(res));
+        } 
+        Ok(())
+    }
+
+    /// Wrapper for libc::pthread_cond_broadcast()
+    pub fn kv_cond_broadcast(cond: &mut pthread_cond_t) -> Result<(), Errno> {
+        let res = unsafe {pthread_cond_broadcast(cond)};
+        if res != 0 {
+            return Err(Errno::from_raw(res));
+        } 
+        Ok(())
+    }
+    
     /// Wrapper for libc::sem_init()
     pub fn kv_sem_init(value: usize) -> Result<sem_t, Errno> {
         let mut sem: sem_t = unsafe { std::mem::zeroed() };